@@ -8,7 +8,7 @@ use clap::Parser;
 use flexi_logger::Logger;
 use log_format::my_log_format;
 use std::fs;
-use wasm_bpf_rs::{run_wasm_bpf_module, Config};
+use wasm_bpf_rs::{run_wasm_bpf_module, Config, PrintLevel};
 
 mod log_format;
 
@@ -28,6 +28,11 @@ struct CommandArgs {
     wrapper_module_name: String,
     #[arg(short = 'c', long, help = "Callback export name", default_value_t = String::from("go-callback"))]
     callback_export_name: String,
+    #[arg(
+        long,
+        help = "Attach the struct_ops maps of bpf objects when they are loaded"
+    )]
+    auto_attach_struct_ops: bool,
     #[arg(help = "Arguments that will be passed to the Wasm program")]
     args_to_wasm: Vec<String>,
 }
@@ -47,6 +52,12 @@ fn main() -> anyhow::Result<()> {
         Config {
             callback_export_name: args.callback_export_name,
             wrapper_module_name: args.wrapper_module_name,
+            auto_attach_struct_ops: args.auto_attach_struct_ops,
+            libbpf_log_level: if args.verbose {
+                PrintLevel::Debug
+            } else {
+                PrintLevel::Info
+            },
             ..Default::default()
         },
     )
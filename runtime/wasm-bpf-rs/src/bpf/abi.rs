@@ -0,0 +1,248 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use log::debug;
+
+use crate::{ensure_enough_memory, state::CallerType, utils::CallerUtils};
+
+use super::{WasmPointer, EFAULT};
+
+/// The version of the host functions in the `wasm_bpf` module.
+///
+/// Version 1 has only the functions loading, attaching and polling bpf objects, and operating maps.
+/// Version 2 adds the ABI queries, and the functions of each capability in `CAPABILITIES`
+pub(crate) const ABI_VERSION: i32 = 2;
+
+// The capability bits, named after the groups of host functions they provide.
+// They are part of the ABI, and mustn't be changed
+
+/// wasm_open_bpf_object, wasm_bpf_object_load, wasm_bpf_program_set_autoload,
+/// wasm_bpf_map_set_max_entries
+const CAP_TWO_PHASE_LOAD: u64 = 1 << 0;
+/// wasm_bpf_global_var_get, wasm_bpf_global_var_set
+const CAP_GLOBAL_VAR: u64 = 1 << 1;
+/// wasm_bpf_buffer_poll_multi
+const CAP_POLL_MULTI: u64 = 1 << 2;
+/// wasm_bpf_buffer_poll_with_cpu, wasm_bpf_buffer_lost_count
+const CAP_POLL_WITH_CPU: u64 = 1 << 3;
+/// wasm_bpf_buffer_poll_batch
+const CAP_BATCH_POLL: u64 = 1 << 4;
+/// wasm_bpf_program_attach, wasm_bpf_program_attach_opts, wasm_bpf_link_detach
+const CAP_EXTENDED_ATTACH: u64 = 1 << 5;
+/// wasm_bpf_socket_filter_open, wasm_bpf_socket_filter_read
+const CAP_SOCKET_FILTER: u64 = 1 << 6;
+/// wasm_bpf_program_attach_perf_event
+const CAP_PERF_EVENT: u64 = 1 << 7;
+/// wasm_bpf_ksym_resolve, wasm_bpf_usym_resolve, wasm_bpf_usym_cache_clear
+const CAP_SYMBOLIZE: u64 = 1 << 8;
+/// wasm_bpf_iter_create, wasm_bpf_iter_read, wasm_bpf_iter_close
+const CAP_ITER: u64 = 1 << 9;
+/// wasm_bpf_struct_ops_attach
+const CAP_STRUCT_OPS: u64 = 1 << 10;
+/// wasm_bpf_program_set_attach_target, wasm_bpf_program_fd_by_name, wasm_bpf_program_fd_by_id
+const CAP_ATTACH_TARGET: u64 = 1 << 11;
+/// wasm_bpf_last_error, wasm_bpf_last_errno
+const CAP_LAST_ERROR: u64 = 1 << 12;
+/// wasm_bpf_object_programs, wasm_bpf_object_maps
+const CAP_OBJECT_INFO: u64 = 1 << 13;
+/// wasm_bpf_map_value_to_json, wasm_bpf_btf_to_json
+const CAP_BTF_JSON: u64 = 1 << 14;
+/// The batch commands of wasm_bpf_map_operate
+const CAP_MAP_BATCH: u64 = 1 << 15;
+
+/// The bits in the mask returned by `wasm_bpf_get_capabilities`, and the names returned by
+/// `wasm_bpf_get_features`, of the groups of host functions guests may check before importing them
+pub(crate) const CAPABILITIES: &[(u64, &str)] = &[
+    (CAP_TWO_PHASE_LOAD, "two_phase_load"),
+    (CAP_GLOBAL_VAR, "global_var"),
+    (CAP_POLL_MULTI, "poll_multi"),
+    (CAP_POLL_WITH_CPU, "poll_with_cpu"),
+    (CAP_BATCH_POLL, "batch_poll"),
+    (CAP_EXTENDED_ATTACH, "extended_attach"),
+    (CAP_SOCKET_FILTER, "socket_filter"),
+    (CAP_PERF_EVENT, "perf_event"),
+    (CAP_SYMBOLIZE, "symbolize"),
+    (CAP_ITER, "iter"),
+    (CAP_STRUCT_OPS, "struct_ops"),
+    (CAP_ATTACH_TARGET, "attach_target"),
+    (CAP_LAST_ERROR, "last_error"),
+    (CAP_OBJECT_INFO, "object_info"),
+    (CAP_BTF_JSON, "btf_json"),
+    (CAP_MAP_BATCH, "map_batch"),
+];
+
+/// The ABI level providing a host function
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum AbiLevel {
+    /// Provided since the ABI version
+    Version(i32),
+    /// Provided with the capability bit in `CAPABILITIES`
+    Capability(u64),
+}
+
+/// The host functions in the `wasm_bpf` module and the ABI levels providing them
+const HOST_FUNCTION_LEVELS: &[(&[&str], AbiLevel)] = &[
+    (
+        &[
+            "wasm_load_bpf_object",
+            "wasm_close_bpf_object",
+            "wasm_attach_bpf_program",
+            "wasm_bpf_buffer_poll",
+            "wasm_bpf_map_fd_by_name",
+            "wasm_bpf_map_operate",
+        ],
+        AbiLevel::Version(1),
+    ),
+    (
+        &[
+            "wasm_bpf_get_abi_version",
+            "wasm_bpf_get_capabilities",
+            "wasm_bpf_get_features",
+        ],
+        AbiLevel::Version(2),
+    ),
+    (
+        &[
+            "wasm_open_bpf_object",
+            "wasm_bpf_object_load",
+            "wasm_bpf_program_set_autoload",
+            "wasm_bpf_map_set_max_entries",
+        ],
+        AbiLevel::Capability(CAP_TWO_PHASE_LOAD),
+    ),
+    (
+        &["wasm_bpf_global_var_get", "wasm_bpf_global_var_set"],
+        AbiLevel::Capability(CAP_GLOBAL_VAR),
+    ),
+    (
+        &["wasm_bpf_buffer_poll_multi"],
+        AbiLevel::Capability(CAP_POLL_MULTI),
+    ),
+    (
+        &[
+            "wasm_bpf_buffer_poll_with_cpu",
+            "wasm_bpf_buffer_lost_count",
+        ],
+        AbiLevel::Capability(CAP_POLL_WITH_CPU),
+    ),
+    (
+        &["wasm_bpf_buffer_poll_batch"],
+        AbiLevel::Capability(CAP_BATCH_POLL),
+    ),
+    (
+        &[
+            "wasm_bpf_program_attach",
+            "wasm_bpf_program_attach_opts",
+            "wasm_bpf_link_detach",
+        ],
+        AbiLevel::Capability(CAP_EXTENDED_ATTACH),
+    ),
+    (
+        &["wasm_bpf_socket_filter_open", "wasm_bpf_socket_filter_read"],
+        AbiLevel::Capability(CAP_SOCKET_FILTER),
+    ),
+    (
+        &["wasm_bpf_program_attach_perf_event"],
+        AbiLevel::Capability(CAP_PERF_EVENT),
+    ),
+    (
+        &[
+            "wasm_bpf_ksym_resolve",
+            "wasm_bpf_usym_resolve",
+            "wasm_bpf_usym_cache_clear",
+        ],
+        AbiLevel::Capability(CAP_SYMBOLIZE),
+    ),
+    (
+        &[
+            "wasm_bpf_iter_create",
+            "wasm_bpf_iter_read",
+            "wasm_bpf_iter_close",
+        ],
+        AbiLevel::Capability(CAP_ITER),
+    ),
+    (
+        &["wasm_bpf_struct_ops_attach"],
+        AbiLevel::Capability(CAP_STRUCT_OPS),
+    ),
+    (
+        &[
+            "wasm_bpf_program_set_attach_target",
+            "wasm_bpf_program_fd_by_name",
+            "wasm_bpf_program_fd_by_id",
+        ],
+        AbiLevel::Capability(CAP_ATTACH_TARGET),
+    ),
+    (
+        &["wasm_bpf_last_error", "wasm_bpf_last_errno"],
+        AbiLevel::Capability(CAP_LAST_ERROR),
+    ),
+    (
+        &["wasm_bpf_object_programs", "wasm_bpf_object_maps"],
+        AbiLevel::Capability(CAP_OBJECT_INFO),
+    ),
+    (
+        &["wasm_bpf_map_value_to_json", "wasm_bpf_btf_to_json"],
+        AbiLevel::Capability(CAP_BTF_JSON),
+    ),
+];
+
+/// Get the ABI level providing the host function, or `None` if it's unknown to this runtime
+pub(crate) fn host_function_level(name: &str) -> Option<&'static AbiLevel> {
+    HOST_FUNCTION_LEVELS
+        .iter()
+        .find(|(names, _)| names.contains(&name))
+        .map(|(_, level)| level)
+}
+
+impl std::fmt::Display for AbiLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Version(v) => write!(f, "ABI version {}", v),
+            Self::Capability(bit) => {
+                let name = CAPABILITIES
+                    .iter()
+                    .find(|(v, _)| v == bit)
+                    .map_or("unknown", |(_, name)| *name);
+                write!(f, "capability `{}` (bit {})", name, bit.trailing_zeros())
+            }
+        }
+    }
+}
+
+/// get the version of the host ABI. Functions added later are
+/// reported by `wasm_bpf_get_capabilities`
+pub fn wasm_bpf_get_abi_version() -> i32 {
+    ABI_VERSION
+}
+
+/// get the mask of the capabilities the host supports
+pub fn wasm_bpf_get_capabilities() -> u64 {
+    CAPABILITIES.iter().fold(0, |mask, (bit, _)| mask | bit)
+}
+
+/// get the names of the capabilities the host supports, separated by commas,
+/// like `two_phase_load,global_var`. It's written into `buf` with a terminating null, truncated if needed.
+///
+/// Returns the length of the whole string
+pub fn wasm_bpf_get_features(mut caller: CallerType, buf: WasmPointer, size: u32) -> i32 {
+    let features = CAPABILITIES
+        .iter()
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(",");
+    if size > 0 {
+        ensure_enough_memory!(caller, buf, size, -EFAULT);
+        let copied = features.len().min(size as usize - 1);
+        let mut data = features.as_bytes()[..copied].to_vec();
+        data.push(0);
+        let memory = caller.get_memory().expect("Expected exported `memory`");
+        if let Err(err) = memory.write(&mut caller, buf as usize, &data) {
+            debug!("Failed to write wasm memory: {}", err);
+            return -EFAULT;
+        }
+    }
+    features.len() as i32
+}
@@ -3,16 +3,252 @@
 //! Copyright (c) 2023, eunomia-bpf
 //! All rights reserved.
 //!
-use std::{ffi::CString, os::fd::AsRawFd};
+use std::{
+    ffi::CString,
+    fs::File,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr::NonNull,
+};
 
+use libbpf_rs::{
+    libbpf_sys::{
+        bpf_attach_type, bpf_iter_attach_opts, bpf_iter_link_info, bpf_kprobe_opts, bpf_object,
+        bpf_object__find_program_by_name, bpf_prog_attach, bpf_prog_attach_opts, bpf_prog_detach2,
+        bpf_program, bpf_program__attach_iter, bpf_program__attach_kprobe_opts,
+        bpf_program__expected_attach_type, bpf_xdp_attach, bpf_xdp_attach_opts, bpf_xdp_detach,
+        size_t, BPF_F_ALLOW_MULTI, BPF_F_ALLOW_OVERRIDE, BPF_F_REPLACE, XDP_FLAGS_DRV_MODE,
+        XDP_FLAGS_HW_MODE, XDP_FLAGS_REPLACE, XDP_FLAGS_SKB_MODE, XDP_FLAGS_UPDATE_IF_NOEXIST,
+    },
+    Link, Program, ProgramType, TracepointOpts, UsdtOpts,
+};
 use libc::if_nametoindex;
 use log::debug;
 
-use crate::{ensure_c_str, ensure_program_mut_by_state, state::CallerType};
+use crate::{
+    ensure_c_str, ensure_enough_memory, ensure_program_mut_by_state, state::CallerType,
+    utils::CallerUtils,
+};
 
-use super::{BpfObjectType, WasmString};
+use super::{
+    libbpf_errno,
+    socket_filter::PacketSocket,
+    tc::{self, TcAttachment, TcTarget},
+    uprobe::{self, UsdtTarget},
+    BpfObjectType, WasmPointer, WasmString, EBADF, EFAULT, EINVAL, ENOENT,
+};
+
+/// A program attached by the guest. The program is detached when it's dropped
+// Most attachments are only held to be dropped
+#[allow(dead_code)]
+pub enum Attachment {
+    /// A bpf link
+    Link(Link),
+    /// A xdp program attached through netlink, which doesn't create a bpf link
+    Xdp(XdpAttachment),
+    /// A tc program attached to the clsact qdisc
+    Tc(TcAttachment),
+    /// A program attached to a cgroup with attach flags, which doesn't create a bpf link
+    Cgroup(CgroupAttachment),
+    /// A socket filter attached to a packet socket opened by the runtime
+    Socket(PacketSocket),
+    /// A sk_msg or sk_skb program attached to a sockmap or sockhash, which doesn't create a bpf link
+    Map(MapAttachment),
+    /// A perf_event program attached to perf events on every cpu
+    PerfEvents(Vec<Link>),
+}
+
+/// A xdp program attached to an interface with flags
+pub struct XdpAttachment {
+    ifindex: i32,
+    flags: u32,
+    prog_fd: i32,
+}
+
+impl Drop for XdpAttachment {
+    fn drop(&mut self) {
+        // Only detach the program if it's still the one attached
+        let opts = bpf_xdp_attach_opts {
+            sz: std::mem::size_of::<bpf_xdp_attach_opts>() as size_t,
+            old_prog_fd: self.prog_fd,
+            ..Default::default()
+        };
+        let mode_flags = self.flags & (XDP_FLAGS_SKB_MODE | XDP_FLAGS_DRV_MODE | XDP_FLAGS_HW_MODE);
+        // SAFETY: the options are valid during the call
+        let err = unsafe { bpf_xdp_detach(self.ifindex, mode_flags, &opts) };
+        if err != 0 {
+            debug!("Failed to detach xdp from {}: {}", self.ifindex, err);
+        }
+    }
+}
+
+/// A program attached to a cgroup through `BPF_PROG_ATTACH`
+pub struct CgroupAttachment {
+    prog_fd: i32,
+    cgroup: File,
+    attach_type: bpf_attach_type,
+}
+
+impl Drop for CgroupAttachment {
+    fn drop(&mut self) {
+        // SAFETY: no pointers are passed
+        let err =
+            unsafe { bpf_prog_detach2(self.prog_fd, self.cgroup.as_raw_fd(), self.attach_type) };
+        if err != 0 {
+            debug!("Failed to detach program from cgroup: {}", err);
+        }
+    }
+}
+
+/// A program attached to a sockmap or sockhash through `BPF_PROG_ATTACH`.
+/// The map fd is duplicated, so that the map outlives the attachment
+pub struct MapAttachment {
+    prog_fd: i32,
+    map: OwnedFd,
+    attach_type: bpf_attach_type,
+}
+
+impl Drop for MapAttachment {
+    fn drop(&mut self) {
+        // SAFETY: no pointers are passed
+        let err = unsafe { bpf_prog_detach2(self.prog_fd, self.map.as_raw_fd(), self.attach_type) };
+        if err != 0 {
+            debug!("Failed to detach program from map: {}", err);
+        }
+    }
+}
+
+/// `flags` of `struct wasm_bpf_attach_opts`: attach to the return of the function
+const WASM_BPF_ATTACH_RETPROBE: u32 = 1;
+/// The xdp flags accepted in `struct wasm_bpf_attach_opts`
+const SUPPORTED_XDP_FLAGS: u32 = XDP_FLAGS_UPDATE_IF_NOEXIST
+    | XDP_FLAGS_SKB_MODE
+    | XDP_FLAGS_DRV_MODE
+    | XDP_FLAGS_HW_MODE
+    | XDP_FLAGS_REPLACE;
+/// The cgroup attach flags accepted in `struct wasm_bpf_attach_opts`
+const SUPPORTED_ATTACH_FLAGS: u32 = BPF_F_ALLOW_OVERRIDE | BPF_F_ALLOW_MULTI | BPF_F_REPLACE;
+/// The size of `struct wasm_bpf_attach_opts` known by this runtime
+const ATTACH_OPTS_SIZE: usize = 40;
+
+// The options that can be set, for checking what a program type accepts
+const OPT_PID: u32 = 1 << 0;
+const OPT_COOKIE: u32 = 1 << 1;
+const OPT_OFFSET: u32 = 1 << 2;
+const OPT_RETPROBE: u32 = 1 << 3;
+const OPT_XDP_FLAGS: u32 = 1 << 4;
+const OPT_ATTACH_FLAGS: u32 = 1 << 5;
+const OPT_REPLACE_PROG_FD: u32 = 1 << 6;
+
+/// The options of attaching a program, read from the guest memory.
+///
+/// The layout in the guest, with all fields in little endian:
+/// ```c
+/// struct wasm_bpf_attach_opts {
+///     uint32_t sz;        // sizeof(struct wasm_bpf_attach_opts)
+///     int32_t pid;        // the process traced by uprobes and USDTs, 0 for all processes
+///     uint64_t cookie;    // the value of bpf_get_attach_cookie()
+///     uint64_t offset;    // the offset from the function, or in the binary for uprobes
+///     uint32_t flags;     // WASM_BPF_ATTACH_RETPROBE (1)
+///     uint32_t xdp_flags; // XDP_FLAGS_{UPDATE_IF_NOEXIST,SKB_MODE,DRV_MODE,HW_MODE,REPLACE}
+///     uint32_t attach_flags;  // BPF_F_{ALLOW_OVERRIDE,ALLOW_MULTI,REPLACE} for cgroups
+///     int32_t replace_prog_fd; // the program replaced with BPF_F_REPLACE or XDP_FLAGS_REPLACE
+/// };
+/// ```
+/// `sz` versions the struct: fields beyond it are zero, and bytes beyond the
+/// fields known by the runtime must be zero.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub(crate) struct AttachOpts {
+    pid: i32,
+    cookie: u64,
+    offset: u64,
+    retprobe: bool,
+    xdp_flags: u32,
+    attach_flags: u32,
+    replace_prog_fd: i32,
+}
+
+impl AttachOpts {
+    /// Read the options from the guest memory
+    fn read_from_guest(caller: &mut CallerType, opts: WasmPointer) -> Result<Self, i32> {
+        let memory = caller.get_memory().expect("Expected exported `memory`");
+        let mut size_buf = [0u8; 4];
+        if let Err(err) = memory.read(&mut *caller, opts as usize, &mut size_buf) {
+            debug!("Failed to read attach options: {}", err);
+            return Err(-EFAULT);
+        }
+        let size = u32::from_le_bytes(size_buf) as usize;
+        if size < size_buf.len() {
+            debug!("Invalid size of attach options: {}", size);
+            return Err(-EINVAL);
+        }
+        ensure_enough_memory!(*caller, opts, size, Err(-EFAULT));
+        // Only the fields known by the runtime are copied, and the rest is checked in place
+        let mut buf = [0u8; ATTACH_OPTS_SIZE];
+        let known = size.min(ATTACH_OPTS_SIZE);
+        if let Err(err) = memory.read(&mut *caller, opts as usize, &mut buf[..known]) {
+            debug!("Failed to read attach options: {}", err);
+            return Err(-EFAULT);
+        }
+        let unknown = opts as usize + known..opts as usize + size;
+        if memory.data(&*caller)[unknown].iter().any(|v| *v != 0) {
+            debug!("Unknown fields are set in attach options");
+            return Err(-EINVAL);
+        }
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+        let flags = u32_at(24);
+        let xdp_flags = u32_at(28);
+        let attach_flags = u32_at(32);
+        if flags & !WASM_BPF_ATTACH_RETPROBE != 0
+            || xdp_flags & !SUPPORTED_XDP_FLAGS != 0
+            || attach_flags & !SUPPORTED_ATTACH_FLAGS != 0
+        {
+            debug!(
+                "Unknown flags {:#x}, xdp flags {:#x} or attach flags {:#x}",
+                flags, xdp_flags, attach_flags
+            );
+            return Err(-EINVAL);
+        }
+        let replace_prog_fd = u32_at(36) as i32;
+        let replace = xdp_flags & XDP_FLAGS_REPLACE != 0 || attach_flags & BPF_F_REPLACE != 0;
+        if replace != (replace_prog_fd != 0) {
+            debug!(
+                "replace_prog_fd {} requires BPF_F_REPLACE or XDP_FLAGS_REPLACE, and vice versa",
+                replace_prog_fd
+            );
+            return Err(-EINVAL);
+        }
+        Ok(Self {
+            pid: u32_at(4) as i32,
+            cookie: u64_at(8),
+            offset: u64_at(16),
+            retprobe: flags & WASM_BPF_ATTACH_RETPROBE != 0,
+            xdp_flags,
+            attach_flags,
+            replace_prog_fd,
+        })
+    }
+    /// Whether only the options in `allowed` are set
+    fn only_set(&self, allowed: u32) -> bool {
+        let set = [
+            (OPT_PID, self.pid != 0),
+            (OPT_COOKIE, self.cookie != 0),
+            (OPT_OFFSET, self.offset != 0),
+            (OPT_RETPROBE, self.retprobe),
+            (OPT_XDP_FLAGS, self.xdp_flags != 0),
+            (OPT_ATTACH_FLAGS, self.attach_flags != 0),
+            (OPT_REPLACE_PROG_FD, self.replace_prog_fd != 0),
+        ];
+        set.iter().all(|(opt, set)| !set || allowed & opt != 0)
+    }
+}
 
 /// attach a bpf program to hook points
+///
+/// The link is owned by the object, and detached when the object is closed.
+/// Use `wasm_bpf_program_attach` to get a handle of the link
 pub fn wasm_attach_bpf_program(
     mut caller: CallerType,
     program: BpfObjectType,
@@ -21,77 +257,235 @@ pub fn wasm_attach_bpf_program(
 ) -> i32 {
     debug!("wasm attach bpf program");
     let name_str = ensure_c_str!(caller, name);
+    let attach_target_str = if attach_target == 0 {
+        None
+    } else {
+        Some(ensure_c_str!(caller, attach_target))
+    };
+    let opts = AttachOpts::default();
+    match attach_program_by_name(&mut caller, program, &name_str, attach_target_str, &opts) {
+        Ok(link) => {
+            store_link(&mut caller, program, link);
+            0
+        }
+        Err(e) => e,
+    }
+}
 
+/// attach a bpf program to hook points, and return a handle of the link.
+///
+/// The handle can be passed to `wasm_bpf_link_detach` to detach the program alone.
+/// Returns a positive link handle, or a negative value if failed
+pub fn wasm_bpf_program_attach(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    attach_target: WasmString, // Allow null pointers
+) -> i32 {
+    debug!("wasm bpf program attach");
+    let name_str = ensure_c_str!(caller, name);
+    let attach_target_str = if attach_target == 0 {
+        None
+    } else {
+        Some(ensure_c_str!(caller, attach_target))
+    };
+    let opts = AttachOpts::default();
+    match attach_program_by_name(&mut caller, program, &name_str, attach_target_str, &opts) {
+        Ok(link) => store_link(&mut caller, program, link),
+        Err(e) => e,
+    }
+}
+
+/// attach a bpf program with the options in `struct wasm_bpf_attach_opts`,
+/// and return a handle of the link. `opts` may be null.
+///
+/// Returns a positive link handle, or a negative value if failed
+pub fn wasm_bpf_program_attach_opts(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    attach_target: WasmString, // Allow null pointers
+    opts: WasmPointer,         // Allow null pointers
+) -> i32 {
+    debug!("wasm bpf program attach with options");
+    let name_str = ensure_c_str!(caller, name);
     let attach_target_str = if attach_target == 0 {
         None
     } else {
         Some(ensure_c_str!(caller, attach_target))
     };
+    let opts = if opts == 0 {
+        AttachOpts::default()
+    } else {
+        match AttachOpts::read_from_guest(&mut caller, opts) {
+            Ok(v) => v,
+            Err(e) => return e,
+        }
+    };
+    debug!("Attach options: {:?}", opts);
+    match attach_program_by_name(&mut caller, program, &name_str, attach_target_str, &opts) {
+        Ok(link) => store_link(&mut caller, program, link),
+        Err(e) => e,
+    }
+}
+
+/// detach a link created by `wasm_bpf_program_attach`
+pub fn wasm_bpf_link_detach(mut caller: CallerType, link: i32) -> i32 {
+    debug!("Detach link: {}", link);
+    for object in caller.data_mut().object_map.values_mut() {
+        if let Some(link) = object.links.remove(&link) {
+            // Dropping the link detaches the program
+            drop(link);
+            return 0;
+        }
+    }
+    debug!("Invalid link handle: {}", link);
+    -EBADF
+}
+
+/// Put a link into the object which the program belongs to, and return the handle of it
+pub(super) fn store_link(caller: &mut CallerType, program: BpfObjectType, link: Attachment) -> i32 {
     let state = caller.data_mut();
+    let link_id = state.next_link_id;
+    state.next_link_id += 1;
     let object = ensure_program_mut_by_state!(state, program);
+    object.links.insert(link_id, link);
+    link_id
+}
+
+/// Attach the program `name` of the object, according to its section, the attach target and the options
+fn attach_program_by_name(
+    caller: &mut CallerType,
+    program: BpfObjectType,
+    name_str: &str,
+    attach_target_str: Option<String>,
+    opts: &AttachOpts,
+) -> Result<Attachment, i32> {
+    let state = caller.data_mut();
+    let object = match state.object_map.get_mut(&program) {
+        Some(v) => v,
+        None => {
+            debug!("Invalid program: {}", program);
+            return Err(-EBADF);
+        }
+    };
     let mut object_guard = object.get_object_mut();
-    let program = match object_guard.prog_mut(&name_str) {
+    let object_ptr = object_guard.as_libbpf_bpf_object_ptr();
+    let program = match object_guard.prog_mut(name_str) {
         Some(v) => v,
         None => {
             debug!("No program named `{}` found", name_str);
-            return -1;
+            return Err(-ENOENT);
         }
     };
+    // Uprobes and USDTs take the binary and the probe from the attach target
+    let user_probe_target = (is_uprobe(program) || is_usdt(program))
+        && attach_target_str.as_deref().is_some_and(|v| !v.is_empty());
+    if *opts != AttachOpts::default() || user_probe_target {
+        return attach_program_with_opts(
+            object_ptr,
+            program,
+            name_str,
+            attach_target_str.as_deref(),
+            opts,
+        );
+    }
     if let Some(attach_target) = attach_target_str {
         let section_name = program.section();
         // More attach types could be added
         match section_name {
-            "sockops" => {
-                let cgroup_file = match std::fs::OpenOptions::new().read(true).open(&attach_target)
+            // `SEC("tc")` and `SEC("classifier")`
+            _ if matches!(program.prog_type(), ProgramType::SchedCls) => {
+                debug!("Processing tc attach to {:?}", attach_target);
+                let target = match TcTarget::parse(&attach_target) {
+                    Some(v) => v,
+                    None => {
+                        debug!("`interface:ingress` or `interface:egress` is required for tc");
+                        return Err(-EINVAL);
+                    }
+                };
+                let ifidx = interface_index(target.interface)?;
+                return tc::attach_tc(program.fd(), ifidx, &target).map(Attachment::Tc);
+            }
+            _ if is_cgroup_program(program) => {
+                return attach_cgroup(
+                    object_ptr,
+                    program,
+                    name_str,
+                    &attach_target,
+                    &AttachOpts::default(),
+                );
+            }
+            _ if is_sockmap_program(program) => {
+                debug!("Processing sockmap attach to {:?}", attach_target);
+                let map_fd = match attach_target
+                    .strip_prefix("map:")
+                    .and_then(|v| v.parse().ok())
                 {
+                    Some(v) => v,
+                    None => {
+                        debug!("`map:<fd>` is required for sk_msg and sk_skb programs");
+                        return Err(-EINVAL);
+                    }
+                };
+                let prog = program_ptr(object_ptr, name_str)?;
+                // SAFETY: the program pointer is valid
+                let attach_type = unsafe { bpf_program__expected_attach_type(prog.as_ptr()) };
+                return attach_to_map(program.fd(), map_fd, attach_type).map(Attachment::Map);
+            }
+            // Iterators of map elements, like `SEC("iter/bpf_map_elem")`, take the map
+            _ if is_iter(program) && !attach_target.is_empty() => {
+                debug!("Processing iterator attach to {:?}", attach_target);
+                let map_fd = match attach_target
+                    .strip_prefix("map:")
+                    .and_then(|v| v.parse().ok())
+                {
+                    Some(v) => v,
+                    None => {
+                        debug!("`map:<fd>` is required for map iterators");
+                        return Err(-EINVAL);
+                    }
+                };
+                let prog = program_ptr(object_ptr, name_str)?;
+                return attach_map_iter(prog, map_fd).map(Attachment::Link);
+            }
+            _ if is_netns_program(program) => {
+                debug!("Processing netns attach to {:?}", attach_target);
+                let netns_path = match attach_target.strip_prefix("netns:") {
+                    Some(v) => v,
+                    None => {
+                        debug!("`netns:<path>` is required for sk_lookup and flow_dissector");
+                        return Err(-EINVAL);
+                    }
+                };
+                let netns = match File::open(netns_path) {
                     Ok(v) => v,
                     Err(err) => {
-                        debug!(
-                            "Failed to open cgroup `{}` for attaching: {}",
-                            attach_target, err
-                        );
-                        return -1;
+                        debug!("Failed to open netns `{}`: {}", netns_path, err);
+                        return Err(-err.raw_os_error().unwrap_or(ENOENT));
                     }
                 };
-                let fd = cgroup_file.as_raw_fd();
-                state.opened_files.push(cgroup_file);
-                let link = match program.attach_cgroup(fd) {
-                    Ok(v) => v,
+                // The link holds the netns, so it's not needed to keep the file opened
+                return match program.attach_netns(netns.as_raw_fd()) {
+                    Ok(v) => Ok(Attachment::Link(v)),
                     Err(err) => {
-                        debug!("Failed to attach program to cgroup: {}", err);
-                        return -1;
+                        debug!("Failed to attach program to netns: {}", err);
+                        Err(libbpf_errno(&err))
                     }
                 };
-                debug!("secops attached with link {:?}", link);
-                state.opened_links.push(link);
-                return 0;
             }
             "xdp" => {
                 debug!("Processing xdp attach to {:?}", attach_target);
-                let name_str = match CString::new(attach_target.as_bytes()) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        debug!("Failed to convert xdp interface name to CStr: {}", e);
-                        return -1;
-                    }
-                };
-                // SAFETY: The input string is guaranteed to be correct
-                let ifidx = unsafe { if_nametoindex(name_str.as_ptr()) };
-                if ifidx == 0 {
-                    let e = errno::errno();
-                    debug!("Failed to get if idx, err={}, errno={}", e, e.0);
-                    return -e.0;
-                }
-                let link = match program.attach_xdp(ifidx as i32) {
+                let ifidx = interface_index(&attach_target)?;
+                let link = match program.attach_xdp(ifidx) {
                     Ok(v) => v,
                     Err(e) => {
                         debug!("Failed to attach xdp: {}", e);
-                        return -1;
+                        return Err(libbpf_errno(&e));
                     }
                 };
                 debug!("xdp attached with link {:?}", link);
-                state.opened_links.push(link);
-                return 0;
+                return Ok(Attachment::Link(link));
             }
             s => {
                 debug!(
@@ -101,13 +495,383 @@ pub fn wasm_attach_bpf_program(
             }
         }
     }
-    let link = match program.attach() {
-        Ok(v) => v,
+    match program.attach() {
+        Ok(v) => Ok(Attachment::Link(v)),
         Err(err) => {
             debug!("Failed to attach link: {}", err);
-            return -1;
+            Err(libbpf_errno(&err))
+        }
+    }
+}
+
+/// Get the index of a network interface by name
+pub(crate) fn interface_index(interface: &str) -> Result<i32, i32> {
+    let name_str = match CString::new(interface.as_bytes()) {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("Failed to convert interface name to CStr: {}", e);
+            return Err(-EINVAL);
+        }
+    };
+    // SAFETY: The input string is guaranteed to be correct
+    let ifidx = unsafe { if_nametoindex(name_str.as_ptr()) };
+    if ifidx == 0 {
+        let e = errno::errno();
+        debug!("Failed to get if idx, err={}, errno={}", e, e.0);
+        return Err(-e.0);
+    }
+    Ok(ifidx as i32)
+}
+
+/// Whether the program is a uprobe or uretprobe
+fn is_uprobe(program: &Program) -> bool {
+    let section = program.section();
+    matches!(program.prog_type(), ProgramType::Kprobe)
+        && (section.starts_with("uprobe") || section.starts_with("uretprobe"))
+}
+
+/// Whether the program is attached to cgroups, like `SEC("sockops")` or `SEC("cgroup_skb/ingress")`
+fn is_cgroup_program(program: &Program) -> bool {
+    matches!(
+        program.prog_type(),
+        ProgramType::SockOps
+            | ProgramType::CgroupSkb
+            | ProgramType::CgroupSock
+            | ProgramType::CgroupSockAddr
+            | ProgramType::CgroupDevice
+            | ProgramType::CgroupSysctl
+            | ProgramType::CgroupSockopt
+    )
+}
+
+/// Whether the program is attached to sockmaps or sockhashes, like `SEC("sk_msg")`
+fn is_sockmap_program(program: &Program) -> bool {
+    matches!(program.prog_type(), ProgramType::SkMsg | ProgramType::SkSkb)
+}
+
+/// Whether the program is an iterator, like `SEC("iter/task")`
+fn is_iter(program: &Program) -> bool {
+    let section = program.section();
+    matches!(program.prog_type(), ProgramType::Tracing)
+        && (section.starts_with("iter/") || section.starts_with("iter.s/"))
+}
+
+/// Attach an iterator program to walk the elements of the map `map_fd`
+fn attach_map_iter(prog: NonNull<bpf_program>, map_fd: u32) -> Result<Link, i32> {
+    let mut link_info = bpf_iter_link_info::default();
+    link_info.map.map_fd = map_fd;
+    let opts = bpf_iter_attach_opts {
+        sz: std::mem::size_of::<bpf_iter_attach_opts>() as size_t,
+        link_info: &mut link_info,
+        link_info_len: std::mem::size_of::<bpf_iter_link_info>() as u32,
+        ..Default::default()
+    };
+    // SAFETY: the link info and the options live through the call
+    let link = unsafe { bpf_program__attach_iter(prog.as_ptr(), &opts) };
+    match NonNull::new(link) {
+        // SAFETY: the link is just created by libbpf
+        Some(v) => Ok(unsafe { Link::from_ptr(v) }),
+        None => {
+            let e = errno::errno();
+            debug!("Failed to attach iterator to map {}: {}", map_fd, e);
+            Err(-e.0)
+        }
+    }
+}
+
+/// Whether the program is attached to network namespaces, like `SEC("sk_lookup")`
+fn is_netns_program(program: &Program) -> bool {
+    matches!(
+        program.prog_type(),
+        ProgramType::SkLookup | ProgramType::FlowDissector
+    )
+}
+
+/// Attach a program to the sockmap or sockhash `map_fd` with `attach_type`,
+/// like `BPF_SK_MSG_VERDICT` or `BPF_SK_SKB_STREAM_PARSER`
+pub(crate) fn attach_to_map(
+    prog_fd: i32,
+    map_fd: i32,
+    attach_type: bpf_attach_type,
+) -> Result<MapAttachment, i32> {
+    // SAFETY: no pointers are passed
+    let fd = unsafe { libc::fcntl(map_fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        let e = errno::errno();
+        debug!("Invalid map fd {}: {}", map_fd, e);
+        return Err(-e.0);
+    }
+    // SAFETY: the fd is just duplicated, and is owned by nobody else
+    let map = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: no pointers are passed
+    let err = unsafe { bpf_prog_attach(prog_fd, map.as_raw_fd(), attach_type, 0) };
+    if err != 0 {
+        debug!("Failed to attach program to map {}: {}", map_fd, err);
+        return Err(err);
+    }
+    Ok(MapAttachment {
+        prog_fd,
+        map,
+        attach_type,
+    })
+}
+
+/// Attach a cgroup program to the cgroup at `cgroup_path`.
+/// A bpf link is created without attach flags; `BPF_PROG_ATTACH` is used with them
+fn attach_cgroup(
+    object_ptr: NonNull<bpf_object>,
+    program: &mut Program,
+    name_str: &str,
+    cgroup_path: &str,
+    opts: &AttachOpts,
+) -> Result<Attachment, i32> {
+    let cgroup = match File::open(cgroup_path) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!(
+                "Failed to open cgroup `{}` for attaching: {}",
+                cgroup_path, err
+            );
+            return Err(-err.raw_os_error().unwrap_or(ENOENT));
         }
     };
-    state.opened_links.push(link);
-    0
+    if opts.attach_flags == 0 {
+        // The link holds the cgroup, so it's not needed to keep the file opened
+        return match program.attach_cgroup(cgroup.as_raw_fd()) {
+            Ok(v) => {
+                debug!("cgroup program attached with link {:?}", v);
+                Ok(Attachment::Link(v))
+            }
+            Err(err) => {
+                debug!("Failed to attach program to cgroup: {}", err);
+                Err(libbpf_errno(&err))
+            }
+        };
+    }
+    let prog = program_ptr(object_ptr, name_str)?;
+    // SAFETY: the program pointer is valid
+    let attach_type = unsafe { bpf_program__expected_attach_type(prog.as_ptr()) };
+    let attach_opts = bpf_prog_attach_opts {
+        sz: std::mem::size_of::<bpf_prog_attach_opts>() as size_t,
+        flags: opts.attach_flags,
+        replace_prog_fd: opts.replace_prog_fd,
+    };
+    let prog_fd = program.fd();
+    // SAFETY: the options live through the call
+    let err =
+        unsafe { bpf_prog_attach_opts(prog_fd, cgroup.as_raw_fd(), attach_type, &attach_opts) };
+    if err != 0 {
+        debug!(
+            "Failed to attach program to cgroup with flags {:#x}: {}",
+            opts.attach_flags, err
+        );
+        return Err(err);
+    }
+    Ok(Attachment::Cgroup(CgroupAttachment {
+        prog_fd,
+        cgroup,
+        attach_type,
+    }))
+}
+
+/// Whether the program is attached to USDTs
+fn is_usdt(program: &Program) -> bool {
+    matches!(program.prog_type(), ProgramType::Kprobe) && program.section().starts_with("usdt")
+}
+
+/// Get the libbpf pointer of a program of the object
+fn program_ptr(object_ptr: NonNull<bpf_object>, name: &str) -> Result<NonNull<bpf_program>, i32> {
+    let name = CString::new(name).map_err(|_| -EINVAL)?;
+    // SAFETY: the object pointer is valid, and the string lives through the call
+    let prog = unsafe { bpf_object__find_program_by_name(object_ptr.as_ptr(), name.as_ptr()) };
+    NonNull::new(prog).ok_or(-ENOENT)
+}
+
+/// Report attach options not supported by the kind of the program
+fn unsupported_opts(opts: &AttachOpts, name: &str, kind: &str) -> i32 {
+    debug!(
+        "The attach options {:?} are not supported by program `{}` of type {}",
+        opts, name, kind
+    );
+    -EINVAL
+}
+
+/// Attach a program with options, through the attach API of its type which takes them.
+/// The options not making sense to the program type are rejected
+fn attach_program_with_opts(
+    object_ptr: NonNull<bpf_object>,
+    program: &mut Program,
+    name_str: &str,
+    attach_target: Option<&str>,
+    opts: &AttachOpts,
+) -> Result<Attachment, i32> {
+    let section = program.section().to_string();
+    // The part after the program type in the section name, like `do_unlinkat` of `kprobe/do_unlinkat`
+    let section_target = section
+        .split_once('/')
+        .map(|v| v.1)
+        .filter(|v| !v.is_empty());
+    let result = match program.prog_type() {
+        // USDTs and uprobes are kprobe programs too, so their unsupported options must not
+        // fall through to the kprobe arm
+        ProgramType::Kprobe if is_usdt(program) => {
+            if !opts.only_set(OPT_PID | OPT_COOKIE) {
+                return Err(unsupported_opts(opts, name_str, "usdt"));
+            }
+            let target = match attach_target.and_then(UsdtTarget::parse) {
+                Some(v) => v,
+                None => {
+                    debug!("`binary:provider:name` is required for attaching USDTs");
+                    return Err(-EINVAL);
+                }
+            };
+            // libbpf takes -1 for all processes
+            let pid = if opts.pid == 0 { -1 } else { opts.pid };
+            program.attach_usdt_with_opts(
+                pid,
+                target.binary,
+                target.provider,
+                target.name,
+                UsdtOpts {
+                    cookie: opts.cookie,
+                    ..Default::default()
+                },
+            )
+        }
+        ProgramType::Kprobe if is_uprobe(program) => {
+            if !opts.only_set(OPT_PID | OPT_COOKIE | OPT_OFFSET | OPT_RETPROBE) {
+                return Err(unsupported_opts(opts, name_str, "uprobe"));
+            }
+            let target = match attach_target {
+                Some(v) => v,
+                None => {
+                    debug!("The binary is required for attaching uprobes");
+                    return Err(-EINVAL);
+                }
+            };
+            // libbpf takes -1 for all processes
+            let pid = if opts.pid == 0 { -1 } else { opts.pid };
+            let prog = program_ptr(object_ptr, name_str)?;
+            return uprobe::attach_uprobe(
+                prog,
+                target,
+                pid,
+                opts.cookie,
+                opts.offset,
+                opts.retprobe || section.starts_with("uretprobe"),
+            )
+            .map(Attachment::Link);
+        }
+        ProgramType::Kprobe if opts.only_set(OPT_COOKIE | OPT_OFFSET | OPT_RETPROBE) => {
+            let func_name = match attach_target.or(section_target) {
+                Some(v) => v,
+                None => {
+                    debug!("The function to probe is required for attaching kprobes");
+                    return Err(-EINVAL);
+                }
+            };
+            let func_name = CString::new(func_name).map_err(|_| -EINVAL)?;
+            let kprobe_opts = bpf_kprobe_opts {
+                sz: std::mem::size_of::<bpf_kprobe_opts>() as size_t,
+                bpf_cookie: opts.cookie,
+                offset: opts.offset as size_t,
+                retprobe: opts.retprobe || section.starts_with("kretprobe"),
+                ..Default::default()
+            };
+            let prog = program_ptr(object_ptr, name_str)?;
+            // SAFETY: the string and the options live through the call
+            let link = unsafe {
+                bpf_program__attach_kprobe_opts(prog.as_ptr(), func_name.as_ptr(), &kprobe_opts)
+            };
+            return match NonNull::new(link) {
+                // SAFETY: the link is just created by libbpf
+                Some(v) => Ok(Attachment::Link(unsafe { Link::from_ptr(v) })),
+                None => {
+                    let e = errno::errno();
+                    debug!("Failed to attach kprobe: {}", e);
+                    Err(-e.0)
+                }
+            };
+        }
+        ProgramType::Tracepoint if opts.only_set(OPT_COOKIE) => {
+            // Accept both `category/name` and `category:name`
+            let tracepoint = attach_target.or(section_target);
+            let (category, name) =
+                match tracepoint.and_then(|v| v.split_once('/').or_else(|| v.split_once(':'))) {
+                    Some(v) => v,
+                    None => {
+                        debug!("The tracepoint to attach is required");
+                        return Err(-EINVAL);
+                    }
+                };
+            program.attach_tracepoint_with_opts(
+                category,
+                name,
+                TracepointOpts {
+                    cookie: opts.cookie,
+                    ..Default::default()
+                },
+            )
+        }
+        ProgramType::Xdp if opts.only_set(OPT_XDP_FLAGS | OPT_REPLACE_PROG_FD) => {
+            let ifindex = match attach_target {
+                Some(v) => interface_index(v)?,
+                None => {
+                    debug!("The interface is required for attaching xdp");
+                    return Err(-EINVAL);
+                }
+            };
+            if opts.xdp_flags == 0 {
+                program.attach_xdp(ifindex)
+            } else {
+                let prog_fd = program.fd();
+                // The program is replaced only if `replace_prog_fd` is still attached
+                let xdp_opts = bpf_xdp_attach_opts {
+                    sz: std::mem::size_of::<bpf_xdp_attach_opts>() as size_t,
+                    old_prog_fd: opts.replace_prog_fd,
+                    ..Default::default()
+                };
+                // SAFETY: the options live through the call
+                let err = unsafe { bpf_xdp_attach(ifindex, prog_fd, opts.xdp_flags, &xdp_opts) };
+                if err != 0 {
+                    debug!(
+                        "Failed to attach xdp with flags {:#x}: {}",
+                        opts.xdp_flags, err
+                    );
+                    return Err(err);
+                }
+                return Ok(Attachment::Xdp(XdpAttachment {
+                    ifindex,
+                    flags: opts.xdp_flags,
+                    prog_fd,
+                }));
+            }
+        }
+        _ if is_cgroup_program(program)
+            && opts.only_set(OPT_ATTACH_FLAGS | OPT_REPLACE_PROG_FD) =>
+        {
+            let cgroup_path = match attach_target {
+                Some(v) => v,
+                None => {
+                    debug!("The cgroup path is required for attaching cgroup programs");
+                    return Err(-EINVAL);
+                }
+            };
+            return attach_cgroup(object_ptr, program, name_str, cgroup_path, opts);
+        }
+        prog_type => {
+            return Err(unsupported_opts(
+                opts,
+                name_str,
+                &format!("{:?}", prog_type),
+            ))
+        }
+    };
+    match result {
+        Ok(v) => Ok(Attachment::Link(v)),
+        Err(err) => {
+            debug!("Failed to attach with options: {}", err);
+            Err(libbpf_errno(&err))
+        }
+    }
 }
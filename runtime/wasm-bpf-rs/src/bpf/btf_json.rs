@@ -0,0 +1,547 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::{
+    ffi::{CStr, CString},
+    ptr::NonNull,
+};
+
+use libbpf_rs::libbpf_sys::{
+    bpf_map, bpf_map__btf_value_type_id, bpf_map__fd, bpf_object, bpf_object__btf,
+    bpf_object__next_map, btf, btf__find_by_name, btf__find_by_name_kind, btf__name_by_offset,
+    btf__resolve_size, btf__resolve_type, btf__type_by_id, btf_array, btf_enum, btf_enum64,
+    btf_member, btf_type, btf_var_secinfo, BTF_INT_BOOL, BTF_INT_CHAR, BTF_INT_SIGNED,
+    BTF_KIND_ARRAY, BTF_KIND_CONST, BTF_KIND_DATASEC, BTF_KIND_ENUM, BTF_KIND_ENUM64,
+    BTF_KIND_FLOAT, BTF_KIND_INT, BTF_KIND_PTR, BTF_KIND_RESTRICT, BTF_KIND_STRUCT,
+    BTF_KIND_TYPEDEF, BTF_KIND_TYPE_TAG, BTF_KIND_UNION, BTF_KIND_VAR, BTF_KIND_VOLATILE,
+};
+use log::debug;
+use serde_json::{Map, Value};
+
+use crate::{
+    ensure_c_str, ensure_enough_memory, ensure_program_by_caller, state::CallerType,
+    utils::CallerUtils,
+};
+
+use super::{BpfObjectType, WasmPointer, WasmString, EBADF, EFAULT, EINVAL, ENOENT};
+
+/// The max depth of nested types decoded, which bounds the recursion on malformed BTF
+const MAX_DECODE_DEPTH: usize = 32;
+
+/// The max count of elements decoded from an array of zero-sized elements,
+/// which isn't bounded by the size of the data
+const MAX_ZERO_SIZED_ELEMS: usize = 1024;
+
+/// The names of char types. Clang doesn't set `BTF_INT_CHAR` for them
+const CHAR_TYPE_NAMES: [&str; 3] = ["char", "signed char", "unsigned char"];
+
+fn btf_kind(t: &btf_type) -> u32 {
+    (t.info >> 24) & 0x1f
+}
+
+fn btf_vlen(t: &btf_type) -> usize {
+    (t.info & 0xffff) as usize
+}
+
+fn btf_kflag(t: &btf_type) -> bool {
+    t.info >> 31 != 0
+}
+
+/// Get the encoding of an int type
+fn btf_int_encoding(t: &btf_type) -> u32 {
+    // SAFETY: the encoding of an int is placed right after the type
+    unsafe { *((t as *const btf_type).add(1) as *const u32) }
+}
+
+/// Read `bits` bits starting at `bit_offset` of little-endian data
+fn read_bits(data: &[u8], bit_offset: usize, bits: usize) -> Result<u128, i32> {
+    let shift = bit_offset % 8;
+    if bits == 0 || shift + bits > 128 {
+        debug!("Unsupported integer of {} bits", bits);
+        return Err(-EINVAL);
+    }
+    let bytes = data
+        .get(bit_offset / 8..(bit_offset + bits).div_ceil(8))
+        .ok_or(-EINVAL)?;
+    let mut raw = [0u8; 16];
+    raw[..bytes.len()].copy_from_slice(bytes);
+    let value = u128::from_le_bytes(raw) >> shift;
+    Ok(if bits == 128 {
+        value
+    } else {
+        value & ((1 << bits) - 1)
+    })
+}
+
+/// Convert an integer to JSON. Values out of the range of i64 and u64 become strings,
+/// since JSON numbers can't hold them
+fn int_to_json(raw: u128, bits: usize, signed: bool) -> Value {
+    if signed {
+        let shift = 128 - bits;
+        let value = ((raw << shift) as i128) >> shift;
+        i64::try_from(value)
+            .map(Value::from)
+            .unwrap_or_else(|_| Value::from(value.to_string()))
+    } else {
+        u64::try_from(raw)
+            .map(Value::from)
+            .unwrap_or_else(|_| Value::from(raw.to_string()))
+    }
+}
+
+/// Decodes raw bytes into JSON by the BTF of a bpf object:
+/// - integers become numbers, `bool` becomes a boolean, and pointers become addresses
+/// - arrays of `char`, `signed char` and `unsigned char` become strings, stopped at the first
+///   null; other arrays become arrays
+/// - structs and unions become objects, with the members of anonymous ones merged into them
+/// - enums become the names of the values, or numbers if no name matches
+/// - datasecs like `.rodata` become objects of their variables
+pub(crate) struct BtfDecoder {
+    btf: NonNull<btf>,
+}
+
+impl BtfDecoder {
+    /// Get the decoder of an object. It mustn't outlive the object
+    ///
+    /// # Safety
+    /// `obj` must point to a valid bpf object
+    pub(crate) unsafe fn from_object(obj: NonNull<bpf_object>) -> Result<Self, i32> {
+        match NonNull::new(bpf_object__btf(obj.as_ptr())) {
+            Some(btf) => Ok(Self::from_btf(btf)),
+            None => {
+                debug!("The bpf object doesn't carry BTF");
+                Err(-ENOENT)
+            }
+        }
+    }
+
+    /// Get the decoder of BTF. It mustn't outlive the BTF
+    pub(crate) fn from_btf(btf: NonNull<btf>) -> Self {
+        Self { btf }
+    }
+
+    /// Find a type by name. `struct `, `union ` and `enum ` prefixes are supported
+    pub(crate) fn find_type(&self, name: &str) -> Result<u32, i32> {
+        let (kind, name) = [
+            ("struct ", BTF_KIND_STRUCT),
+            ("union ", BTF_KIND_UNION),
+            ("enum ", BTF_KIND_ENUM),
+        ]
+        .iter()
+        .find_map(|(prefix, kind)| Some((Some(*kind), name.strip_prefix(prefix)?)))
+        .unwrap_or((None, name));
+        let c_name = CString::new(name).map_err(|_| -EINVAL)?;
+        // SAFETY: the BTF is valid while the object lives, and the name is null-terminated
+        let id = unsafe {
+            match kind {
+                Some(kind) => btf__find_by_name_kind(self.btf.as_ptr(), c_name.as_ptr(), kind),
+                None => btf__find_by_name(self.btf.as_ptr(), c_name.as_ptr()),
+            }
+        };
+        if id <= 0 {
+            debug!("No type named `{}` found in BTF", name);
+            return Err(-ENOENT);
+        }
+        Ok(id as u32)
+    }
+
+    /// Decode `data` as the type `type_id`. Bytes beyond the size of the type are ignored
+    pub(crate) fn decode(&self, type_id: u32, data: &[u8]) -> Result<Value, i32> {
+        // SAFETY: the BTF is valid while the object lives
+        let size = unsafe { btf__resolve_size(self.btf.as_ptr(), type_id) };
+        if size < 0 {
+            debug!("Failed to resolve the size of type {}: {}", type_id, size);
+            return Err(-EINVAL);
+        }
+        if data.len() < size as usize {
+            debug!(
+                "Type {} takes {} bytes, but only {} provided",
+                type_id,
+                size,
+                data.len()
+            );
+            return Err(-EINVAL);
+        }
+        self.decode_type(type_id, data, 0)
+    }
+
+    fn type_by_id(&self, type_id: u32) -> Result<&btf_type, i32> {
+        // SAFETY: libbpf returns null for ids out of range, and the types live as long as the BTF
+        unsafe { btf__type_by_id(self.btf.as_ptr(), type_id).as_ref() }.ok_or(-EINVAL)
+    }
+
+    fn name(&self, name_off: u32) -> String {
+        // SAFETY: the BTF is valid while the object lives
+        let name = unsafe { btf__name_by_offset(self.btf.as_ptr(), name_off) };
+        if name.is_null() {
+            return String::new();
+        }
+        // SAFETY: names in the string section are null-terminated
+        unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Get the type with typedefs and modifiers skipped
+    fn resolve(&self, type_id: u32) -> Result<&btf_type, i32> {
+        // SAFETY: the BTF is valid while the object lives
+        let id = unsafe { btf__resolve_type(self.btf.as_ptr(), type_id) };
+        if id < 0 {
+            return Err(-EINVAL);
+        }
+        self.type_by_id(id as u32)
+    }
+
+    fn type_size(&self, type_id: u32) -> Result<usize, i32> {
+        // SAFETY: the BTF is valid while the object lives
+        let size = unsafe { btf__resolve_size(self.btf.as_ptr(), type_id) };
+        usize::try_from(size).map_err(|_| -EINVAL)
+    }
+
+    /// Decode a type nested in `depth` types
+    fn decode_type(&self, type_id: u32, data: &[u8], depth: usize) -> Result<Value, i32> {
+        if depth >= MAX_DECODE_DEPTH {
+            debug!("Types are nested too deep at type {}", type_id);
+            return Err(-EINVAL);
+        }
+        let t = self.type_by_id(type_id)?;
+        // SAFETY: for types with a size, the union holds the size, otherwise the referenced type
+        let (size, ref_type) = unsafe { (t.__bindgen_anon_1.size, t.__bindgen_anon_1.type_) };
+        match btf_kind(t) {
+            BTF_KIND_INT => self.decode_int(t, data, 0, 0),
+            BTF_KIND_PTR => {
+                let bits = self.type_size(type_id)? * 8;
+                Ok(Value::from(read_bits(data, 0, bits)? as u64))
+            }
+            BTF_KIND_ENUM | BTF_KIND_ENUM64 => self.decode_enum(t, data, 0, 0),
+            BTF_KIND_FLOAT => Ok(match size {
+                4 => Value::from(f32::from_bits(read_bits(data, 0, 32)? as u32)),
+                8 => Value::from(f64::from_bits(read_bits(data, 0, 64)? as u64)),
+                _ => Value::Null,
+            }),
+            BTF_KIND_ARRAY => self.decode_array(t, data, depth),
+            BTF_KIND_STRUCT | BTF_KIND_UNION => self.decode_struct(t, data, depth),
+            BTF_KIND_DATASEC => self.decode_datasec(t, data, depth),
+            BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST | BTF_KIND_RESTRICT
+            | BTF_KIND_TYPE_TAG | BTF_KIND_VAR => self.decode_type(ref_type, data, depth + 1),
+            kind => {
+                debug!("Type {} of kind {} can't be decoded", type_id, kind);
+                Err(-EINVAL)
+            }
+        }
+    }
+
+    /// Decode an integer. A non-zero `bitfield_size` overrides the bits of the type
+    fn decode_int(
+        &self,
+        t: &btf_type,
+        data: &[u8],
+        bit_offset: usize,
+        bitfield_size: usize,
+    ) -> Result<Value, i32> {
+        let encoding = btf_int_encoding(t);
+        let bits = match bitfield_size {
+            0 => (encoding & 0xff) as usize,
+            v => v,
+        };
+        let bit_offset = bit_offset + ((encoding >> 16) & 0xff) as usize;
+        let raw = read_bits(data, bit_offset, bits)?;
+        let flags = (encoding >> 24) & 0x0f;
+        if flags & BTF_INT_BOOL != 0 {
+            return Ok(Value::from(raw != 0));
+        }
+        Ok(int_to_json(raw, bits, flags & BTF_INT_SIGNED != 0))
+    }
+
+    fn decode_enum(
+        &self,
+        t: &btf_type,
+        data: &[u8],
+        bit_offset: usize,
+        bitfield_size: usize,
+    ) -> Result<Value, i32> {
+        let bits = match bitfield_size {
+            // SAFETY: the union of enums holds the size
+            0 => (unsafe { t.__bindgen_anon_1.size }) as usize * 8,
+            v => v,
+        };
+        let raw = read_bits(data, bit_offset, bits)?;
+        let signed = btf_kflag(t);
+        let value = int_to_json(raw, bits, signed);
+        let vlen = btf_vlen(t);
+        // The values are placed right after the enum type
+        let values: Vec<(u32, i128)> = if btf_kind(t) == BTF_KIND_ENUM {
+            // SAFETY: the values follow the type in the same allocation
+            let enums = unsafe { (t as *const btf_type).add(1) as *const btf_enum };
+            (0..vlen)
+                .map(|i| {
+                    // SAFETY: the enum has `vlen` values
+                    let v = unsafe { &*enums.add(i) };
+                    let val = if signed {
+                        v.val as i128
+                    } else {
+                        v.val as u32 as i128
+                    };
+                    (v.name_off, val)
+                })
+                .collect()
+        } else {
+            // SAFETY: the values follow the type in the same allocation
+            let enums = unsafe { (t as *const btf_type).add(1) as *const btf_enum64 };
+            (0..vlen)
+                .map(|i| {
+                    // SAFETY: the enum has `vlen` values
+                    let v = unsafe { &*enums.add(i) };
+                    let val = ((v.val_hi32 as u64) << 32) | v.val_lo32 as u64;
+                    let val = if signed {
+                        val as i64 as i128
+                    } else {
+                        val as i128
+                    };
+                    (v.name_off, val)
+                })
+                .collect()
+        };
+        let target = if signed {
+            value.as_i64().map(i128::from)
+        } else {
+            value.as_u64().map(i128::from)
+        };
+        Ok(values
+            .iter()
+            .find(|(_, val)| Some(*val) == target)
+            .map(|(name_off, _)| Value::from(self.name(*name_off)))
+            .unwrap_or(value))
+    }
+
+    /// Whether an int type is a char, by the encoding, or the name for objects built by clang
+    fn is_char(&self, t: &btf_type) -> bool {
+        let encoding = btf_int_encoding(t);
+        if (encoding >> 24) & BTF_INT_CHAR != 0 {
+            return true;
+        }
+        encoding & 0xff == 8 && CHAR_TYPE_NAMES.contains(&self.name(t.name_off).as_str())
+    }
+
+    fn decode_array(&self, t: &btf_type, data: &[u8], depth: usize) -> Result<Value, i32> {
+        // SAFETY: the array info is placed right after the type
+        let array = unsafe { &*((t as *const btf_type).add(1) as *const btf_array) };
+        let elem = self.resolve(array.type_)?;
+        let nelems = array.nelems as usize;
+        if btf_kind(elem) == BTF_KIND_INT && self.is_char(elem) {
+            let bytes = &data[..nelems.min(data.len())];
+            let len = bytes.iter().position(|v| *v == 0).unwrap_or(bytes.len());
+            return Ok(Value::from(String::from_utf8_lossy(&bytes[..len])));
+        }
+        let elem_size = self.type_size(array.type_)?;
+        let nelems = if elem_size == 0 {
+            nelems.min(MAX_ZERO_SIZED_ELEMS)
+        } else {
+            match nelems.checked_mul(elem_size) {
+                Some(v) if v <= data.len() => nelems,
+                _ => return Err(-EINVAL),
+            }
+        };
+        (0..nelems)
+            .map(|i| {
+                let elem_data = data.get(i * elem_size..).ok_or(-EINVAL)?;
+                self.decode_type(array.type_, elem_data, depth + 1)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::from)
+    }
+
+    fn decode_struct(&self, t: &btf_type, data: &[u8], depth: usize) -> Result<Value, i32> {
+        let kflag = btf_kflag(t);
+        // SAFETY: the members are placed right after the type
+        let members = unsafe { (t as *const btf_type).add(1) as *const btf_member };
+        let mut fields = Map::new();
+        for i in 0..btf_vlen(t) {
+            // SAFETY: the struct has `vlen` members
+            let member = unsafe { &*members.add(i) };
+            let (bit_offset, bitfield_size) = if kflag {
+                (
+                    (member.offset & 0xffffff) as usize,
+                    (member.offset >> 24) as usize,
+                )
+            } else {
+                (member.offset as usize, 0)
+            };
+            let value = if bitfield_size == 0 && bit_offset % 8 == 0 {
+                let member_data = data.get(bit_offset / 8..).ok_or(-EINVAL)?;
+                self.decode_type(member.type_, member_data, depth + 1)?
+            } else {
+                let member_type = self.resolve(member.type_)?;
+                match btf_kind(member_type) {
+                    BTF_KIND_INT => {
+                        self.decode_int(member_type, data, bit_offset, bitfield_size)?
+                    }
+                    BTF_KIND_ENUM | BTF_KIND_ENUM64 => {
+                        self.decode_enum(member_type, data, bit_offset, bitfield_size)?
+                    }
+                    kind => {
+                        debug!("Bitfield of kind {} can't be decoded", kind);
+                        return Err(-EINVAL);
+                    }
+                }
+            };
+            let name = self.name(member.name_off);
+            match value {
+                // Members of anonymous structs and unions are accessed as members of the parent
+                Value::Object(inner) if name.is_empty() => fields.extend(inner),
+                value => {
+                    fields.insert(name, value);
+                }
+            }
+        }
+        Ok(Value::Object(fields))
+    }
+
+    fn decode_datasec(&self, t: &btf_type, data: &[u8], depth: usize) -> Result<Value, i32> {
+        // SAFETY: the secinfo array is placed right after the datasec type
+        let secinfos = unsafe { (t as *const btf_type).add(1) as *const btf_var_secinfo };
+        let mut vars = Map::new();
+        for i in 0..btf_vlen(t) {
+            // SAFETY: the datasec has `vlen` variables
+            let secinfo = unsafe { &*secinfos.add(i) };
+            let var = self.type_by_id(secinfo.type_)?;
+            let end = secinfo.offset.checked_add(secinfo.size).ok_or(-EINVAL)?;
+            let var_data = data
+                .get(secinfo.offset as usize..end as usize)
+                .ok_or(-EINVAL)?;
+            vars.insert(
+                self.name(var.name_off),
+                self.decode_type(secinfo.type_, var_data, depth + 1)?,
+            );
+        }
+        Ok(Value::Object(vars))
+    }
+}
+
+/// Find the BTF type of the values of a map by its fd
+///
+/// # Safety
+/// `obj` must point to a valid bpf object
+unsafe fn map_value_type_id(obj: NonNull<bpf_object>, map_fd: i32) -> Result<u32, i32> {
+    let mut map: *mut bpf_map = std::ptr::null_mut();
+    loop {
+        map = bpf_object__next_map(obj.as_ptr(), map);
+        if map.is_null() {
+            debug!("No map with fd {} in the object", map_fd);
+            return Err(-EBADF);
+        }
+        if bpf_map__fd(map) != map_fd {
+            continue;
+        }
+        return match bpf_map__btf_value_type_id(map) {
+            0 => {
+                debug!("The map {} doesn't declare a BTF value type", map_fd);
+                Err(-ENOENT)
+            }
+            v => Ok(v),
+        };
+    }
+}
+
+/// Decode the data in the guest memory, send the value to the embedder if it asks,
+/// and write the JSON text into `buf` with a terminating null, truncated if needed.
+///
+/// `find_type` gets the type to decode as from the BTF of the object.
+/// Returns the length of the whole text
+fn decode_to_json(
+    caller: &mut CallerType,
+    program: BpfObjectType,
+    data: WasmPointer,
+    data_size: u32,
+    buf: WasmPointer,
+    buf_size: u32,
+    find_type: impl FnOnce(&BtfDecoder, NonNull<bpf_object>) -> Result<u32, i32>,
+) -> i32 {
+    ensure_enough_memory!(*caller, data, data_size, -EFAULT);
+    ensure_enough_memory!(*caller, buf, buf_size, -EFAULT);
+    let mut bytes = vec![0u8; data_size as usize];
+    let memory = caller.get_memory().expect("Expected exported `memory`");
+    if let Err(err) = memory.read(&mut *caller, data as usize, &mut bytes) {
+        debug!("Failed to read wasm memory: {}", err);
+        return -EFAULT;
+    }
+    let object = ensure_program_by_caller!(caller, program);
+    let obj = object.get_object().as_libbpf_bpf_object_ptr();
+    // SAFETY: the object stays loaded during the call
+    let value = unsafe { BtfDecoder::from_object(obj) }
+        .and_then(|decoder| decoder.decode(find_type(&decoder, obj)?, &bytes));
+    let value = match value {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    let text = value.to_string();
+    if let Some(sender) = &caller.data().json_sender {
+        if sender.send(value).is_err() {
+            debug!("The receiver of decoded values is dropped");
+        }
+    }
+    if buf_size > 0 {
+        let copied = text.len().min(buf_size as usize - 1);
+        let mut out = text.as_bytes()[..copied].to_vec();
+        out.push(0);
+        if let Err(err) = memory.write(&mut *caller, buf as usize, &out) {
+            debug!("Failed to write wasm memory: {}", err);
+            return -EFAULT;
+        }
+    }
+    text.len() as i32
+}
+
+/// decode a value of the map `map_fd` in a loaded bpf object, like one from
+/// `wasm_bpf_map_operate`, into JSON by the BTF value type of the map.
+///
+/// Returns the length of the JSON text, or a negative value if failed
+pub fn wasm_bpf_map_value_to_json(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    map_fd: i32,
+    data: WasmPointer,
+    data_size: u32,
+    buf: WasmPointer,
+    buf_size: u32,
+) -> i32 {
+    debug!("Decode value of map {} into JSON", map_fd);
+    decode_to_json(
+        &mut caller,
+        program,
+        data,
+        data_size,
+        buf,
+        buf_size,
+        // SAFETY: the object stays loaded during the call
+        |_, obj| unsafe { map_value_type_id(obj, map_fd) },
+    )
+}
+
+/// decode data, like a ring buffer sample, into JSON as the type named `type_name`
+/// in the BTF of a loaded bpf object, like `struct event`.
+///
+/// Returns the length of the JSON text, or a negative value if failed
+pub fn wasm_bpf_btf_to_json(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    type_name: WasmString,
+    data: WasmPointer,
+    data_size: u32,
+    buf: WasmPointer,
+    buf_size: u32,
+) -> i32 {
+    let type_name = ensure_c_str!(caller, type_name);
+    debug!("Decode `{}` into JSON", type_name);
+    decode_to_json(
+        &mut caller,
+        program,
+        data,
+        data_size,
+        buf,
+        buf_size,
+        |decoder, _| decoder.find_type(&type_name),
+    )
+}
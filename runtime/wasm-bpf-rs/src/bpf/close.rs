@@ -9,7 +9,7 @@ use log::debug;
 
 use crate::state::CallerType;
 
-use super::BpfObjectType;
+use super::{BpfObjectType, EBADF};
 
 /// close and detach a bpf object
 pub fn wasm_close_bpf_object(mut caller: CallerType, program: BpfObjectType) -> i32 {
@@ -20,9 +20,13 @@ pub fn wasm_close_bpf_object(mut caller: CallerType, program: BpfObjectType) ->
             v.remove();
             0
         }
-        Entry::Vacant(_) => {
-            debug!("Invalid bpf object id: {}", program);
-            -1
-        }
+        // The object may be opened but not loaded yet
+        Entry::Vacant(_) => match state.open_object_map.remove(&program) {
+            Some(_) => 0,
+            None => {
+                debug!("Invalid bpf object id: {}", program);
+                -EBADF
+            }
+        },
     }
 }
@@ -0,0 +1,91 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use log::debug;
+
+use crate::{ensure_c_str, ensure_open_object_mut_by_state, state::CallerType};
+
+use super::{libbpf_errno, BpfObjectType, WasmString, ENOENT};
+
+/// set whether a program in an opened bpf object will be loaded
+pub fn wasm_bpf_program_set_autoload(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    autoload: u32,
+) -> i32 {
+    debug!("program set autoload");
+    let name_str = ensure_c_str!(caller, name);
+    let open_object = ensure_open_object_mut_by_state!(caller.data_mut(), program);
+    let prog = match open_object.prog_mut(&name_str) {
+        Some(v) => v,
+        None => {
+            debug!("No program named `{}` found", name_str);
+            return -ENOENT;
+        }
+    };
+    if let Err(err) = prog.set_autoload(autoload != 0) {
+        debug!("Failed to set autoload of `{}`: {}", name_str, err);
+        return libbpf_errno(&err);
+    }
+    0
+}
+
+/// set the max entries of a map in an opened bpf object
+pub fn wasm_bpf_map_set_max_entries(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    max_entries: u32,
+) -> i32 {
+    debug!("map set max entries");
+    let name_str = ensure_c_str!(caller, name);
+    let open_object = ensure_open_object_mut_by_state!(caller.data_mut(), program);
+    let map = match open_object.map_mut(&name_str) {
+        Some(v) => v,
+        None => {
+            debug!("Invalid map name: {}", name_str);
+            return -ENOENT;
+        }
+    };
+    if let Err(err) = map.set_max_entries(max_entries) {
+        debug!("Failed to set max entries of `{}`: {}", name_str, err);
+        return libbpf_errno(&err);
+    }
+    0
+}
+
+/// set the program or kernel function a program in an opened bpf object attaches to,
+/// for `fentry`, `fexit` and `freplace` programs tracing or replacing other bpf programs.
+/// `target_prog_fd` is 0 for kernel functions, and `func_name` may be null to keep
+/// the function in the section name
+pub fn wasm_bpf_program_set_attach_target(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    target_prog_fd: i32,
+    func_name: WasmString, // Allow null pointers
+) -> i32 {
+    debug!("program set attach target");
+    let name_str = ensure_c_str!(caller, name);
+    let func_name_str = if func_name == 0 {
+        None
+    } else {
+        Some(ensure_c_str!(caller, func_name))
+    };
+    let open_object = ensure_open_object_mut_by_state!(caller.data_mut(), program);
+    let prog = match open_object.prog_mut(&name_str) {
+        Some(v) => v,
+        None => {
+            debug!("No program named `{}` found", name_str);
+            return -ENOENT;
+        }
+    };
+    if let Err(err) = prog.set_attach_target(target_prog_fd, func_name_str) {
+        debug!("Failed to set attach target of `{}`: {}", name_str, err);
+        return libbpf_errno(&err);
+    }
+    0
+}
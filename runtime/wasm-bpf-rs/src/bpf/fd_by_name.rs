@@ -3,11 +3,14 @@
 //! Copyright (c) 2023, eunomia-bpf
 //! All rights reserved.
 //!
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use libbpf_rs::libbpf_sys::bpf_prog_get_fd_by_id;
 use log::debug;
 
 use crate::{ensure_c_str, ensure_program_mut_by_caller, state::CallerType};
 
-use super::{BpfObjectType, WasmString};
+use super::{BpfObjectType, WasmString, ENOENT};
 
 /// get map fd by name from a bpf object
 pub fn wasm_bpf_map_fd_by_name(
@@ -23,9 +26,51 @@ pub fn wasm_bpf_map_fd_by_name(
         Some(v) => v,
         None => {
             debug!("Invalid map name: {}", map_name);
-            return -1;
+            return -ENOENT;
         }
     };
 
     map.fd()
 }
+
+/// get program fd by name from a bpf object
+pub fn wasm_bpf_program_fd_by_name(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+) -> i32 {
+    debug!("program fd by name");
+    let prog_name = ensure_c_str!(caller, name);
+    let object = ensure_program_mut_by_caller!(caller, program);
+    let object_guard = object.get_object();
+    let prog = match object_guard.prog(&prog_name) {
+        Some(v) => v,
+        None => {
+            debug!("Invalid program name: {}", prog_name);
+            return -ENOENT;
+        }
+    };
+
+    prog.fd()
+}
+
+/// get the fd of a bpf program loaded in the kernel by its id, like the ones shown by `bpftool prog`.
+/// The fd is kept by the runtime until it exits, and the same fd is returned for the same id
+pub fn wasm_bpf_program_fd_by_id(mut caller: CallerType, id: u32) -> i32 {
+    debug!("program fd by id: {}", id);
+    let state = caller.data_mut();
+    if let Some(fd) = state.program_fds_by_id.get(&id) {
+        return fd.as_raw_fd();
+    }
+    // SAFETY: no pointers are passed
+    let fd = unsafe { bpf_prog_get_fd_by_id(id) };
+    if fd < 0 {
+        debug!("Failed to get fd of program {}: {}", id, fd);
+        return fd;
+    }
+    // SAFETY: the fd is just created, and is owned by nobody else
+    state
+        .program_fds_by_id
+        .insert(id, unsafe { OwnedFd::from_raw_fd(fd) });
+    fd
+}
@@ -0,0 +1,252 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::{collections::hash_map::Entry, ffi::CStr, ptr::NonNull};
+
+use libbpf_rs::libbpf_sys::{
+    bpf_map, bpf_map__btf_value_type_id, bpf_map__fd, bpf_map__initial_value, bpf_map__is_internal,
+    bpf_map__map_flags, bpf_map__value_size, bpf_object, bpf_object__btf, bpf_object__next_map,
+    btf__name_by_offset, btf__type_by_id, btf_var_secinfo, size_t, BPF_F_MMAPABLE,
+    BPF_F_RDONLY_PROG, BTF_KIND_DATASEC,
+};
+use log::debug;
+
+use crate::{ensure_c_str, ensure_enough_memory, state::CallerType, utils::CallerUtils};
+
+use super::{last_errno, BpfObjectType, WasmPointer, WasmString, EBADF, EFAULT, EINVAL, ENOENT};
+
+/// A datasec map mmaped into the host, so that `.data` and `.bss` variables
+/// can be accessed while the programs are running
+pub struct MmapedDatasec {
+    ptr: NonNull<u8>,
+    size: usize,
+    writable: bool,
+}
+
+impl MmapedDatasec {
+    fn new(map_fd: i32, value_size: usize, writable: bool) -> Result<Self, i32> {
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        // SAFETY: a fresh mapping is created, which doesn't alias anything
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                value_size,
+                prot,
+                libc::MAP_SHARED,
+                map_fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            let e = last_errno();
+            debug!("Failed to mmap datasec map {}: {}", map_fd, e);
+            return Err(e);
+        }
+        Ok(Self {
+            ptr: NonNull::new(ptr as *mut u8).ok_or(-EFAULT)?,
+            size: value_size,
+            writable,
+        })
+    }
+}
+
+impl Drop for MmapedDatasec {
+    fn drop(&mut self) {
+        // SAFETY: the region was mapped by us with the same size
+        unsafe { libc::munmap(self.ptr.as_ptr() as *mut _, self.size) };
+    }
+}
+
+/// Where a global variable lives in a bpf object
+struct GlobalVarLocation {
+    map: NonNull<bpf_map>,
+    offset: usize,
+    size: usize,
+}
+
+/// Find a global variable by walking the BTF datasec of every internal map
+///
+/// # Safety
+/// `obj` must point to a valid bpf object, either opened or loaded
+unsafe fn find_global_var(obj: NonNull<bpf_object>, var_name: &str) -> Option<GlobalVarLocation> {
+    let btf = bpf_object__btf(obj.as_ptr());
+    if btf.is_null() {
+        debug!("The bpf object doesn't carry BTF");
+        return None;
+    }
+    let mut map: *mut bpf_map = std::ptr::null_mut();
+    loop {
+        map = bpf_object__next_map(obj.as_ptr(), map);
+        if map.is_null() {
+            return None;
+        }
+        if !bpf_map__is_internal(map) {
+            continue;
+        }
+        let datasec = btf__type_by_id(btf, bpf_map__btf_value_type_id(map));
+        if datasec.is_null() || ((*datasec).info >> 24) & 0x1f != BTF_KIND_DATASEC {
+            continue;
+        }
+        let vlen = ((*datasec).info & 0xffff) as usize;
+        // The secinfo array is placed right after the datasec type
+        let secinfos = datasec.add(1) as *const btf_var_secinfo;
+        for i in 0..vlen {
+            let secinfo = &*secinfos.add(i);
+            let var = btf__type_by_id(btf, secinfo.type_);
+            if var.is_null() {
+                continue;
+            }
+            let name = btf__name_by_offset(btf, (*var).name_off);
+            if !name.is_null() && CStr::from_ptr(name).to_bytes() == var_name.as_bytes() {
+                return Some(GlobalVarLocation {
+                    map: NonNull::new_unchecked(map),
+                    offset: secinfo.offset as usize,
+                    size: secinfo.size as usize,
+                });
+            }
+        }
+    }
+}
+
+/// Get the host memory holding the datasec that contains the variable `var_name`.
+/// Before loading it's the initial value of the map, after loading it's the mmaped map.
+/// Returns the pointer to the variable and the size of it, or a negative errno
+fn global_var_pointer(
+    caller: &mut CallerType,
+    program: BpfObjectType,
+    var_name: &str,
+    write: bool,
+) -> Result<(*mut u8, usize), i32> {
+    let state = caller.data_mut();
+    if state.open_object_map.contains_key(&program) {
+        let obj = state.open_object_ptr(program).ok_or(-EBADF)?;
+        // SAFETY: the pointer comes from an opened object
+        let location = unsafe { find_global_var(obj, var_name) }.ok_or(-ENOENT)?;
+        let mut size: size_t = 0;
+        // SAFETY: the map belongs to the object. libbpf keeps the initial value in a writable buffer
+        let data = unsafe { bpf_map__initial_value(location.map.as_ptr(), &mut size) } as *mut u8;
+        if data.is_null() || location.offset + location.size > size as usize {
+            debug!("No initial value found for `{}`", var_name);
+            return Err(-ENOENT);
+        }
+        // SAFETY: the variable is checked to be inside the buffer
+        return Ok((unsafe { data.add(location.offset) }, location.size));
+    }
+    let object = match state.object_map.get_mut(&program) {
+        Some(v) => v,
+        None => {
+            debug!("Invalid program: {}", program);
+            return Err(-EBADF);
+        }
+    };
+    let obj = object.get_object().as_libbpf_bpf_object_ptr();
+    // SAFETY: the pointer comes from a loaded object
+    let location = unsafe { find_global_var(obj, var_name) }.ok_or(-ENOENT)?;
+    let map = location.map.as_ptr();
+    // SAFETY: the map belongs to the object
+    let (fd, flags, value_size) = unsafe {
+        (
+            bpf_map__fd(map),
+            bpf_map__map_flags(map),
+            bpf_map__value_size(map) as usize,
+        )
+    };
+    let read_only = flags & BPF_F_RDONLY_PROG != 0;
+    if write && read_only {
+        debug!("`{}` is read only after the object is loaded", var_name);
+        return Err(-EINVAL);
+    }
+    if flags & BPF_F_MMAPABLE == 0 {
+        debug!("The map containing `{}` can't be mmaped", var_name);
+        return Err(-EINVAL);
+    }
+    let mmaped = match object.datasec_mmaps.entry(fd) {
+        Entry::Occupied(v) => v.into_mut(),
+        Entry::Vacant(v) => v.insert(MmapedDatasec::new(fd, value_size, !read_only)?),
+    };
+    if (write && !mmaped.writable) || location.offset + location.size > mmaped.size {
+        return Err(-EINVAL);
+    }
+    // SAFETY: the variable is checked to be inside the mapping
+    Ok((
+        unsafe { mmaped.ptr.as_ptr().add(location.offset) },
+        location.size,
+    ))
+}
+
+/// read a global variable in `.rodata`, `.data` or `.bss` of a bpf object by name
+pub fn wasm_bpf_global_var_get(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    buf: WasmPointer,
+    size: u32,
+) -> i32 {
+    debug!("global var get");
+    let var_name = ensure_c_str!(caller, name);
+    ensure_enough_memory!(caller, buf, size, -EFAULT);
+    let (var_ptr, var_size) = match global_var_pointer(&mut caller, program, &var_name, false) {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("No accessible global variable named `{}`", var_name);
+            return e;
+        }
+    };
+    if size as usize > var_size {
+        debug!(
+            "`{}` has only {} bytes, {} requested",
+            var_name, var_size, size
+        );
+        return -EINVAL;
+    }
+    // SAFETY: the pointer is checked to hold at least `size` bytes
+    let value = unsafe { std::slice::from_raw_parts(var_ptr, size as usize) }.to_vec();
+    let memory = caller.get_memory().expect("Expected exported `memory`");
+    if let Err(err) = memory.write(&mut caller, buf as usize, &value) {
+        debug!("Failed to write wasm memory: {}", err);
+        return -EFAULT;
+    }
+    0
+}
+
+/// write a global variable in `.rodata` (before loading), `.data` or `.bss` of a bpf object by name
+pub fn wasm_bpf_global_var_set(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    buf: WasmPointer,
+    size: u32,
+) -> i32 {
+    debug!("global var set");
+    let var_name = ensure_c_str!(caller, name);
+    ensure_enough_memory!(caller, buf, size, -EFAULT);
+    let mut value = vec![0u8; size as usize];
+    let memory = caller.get_memory().expect("Expected exported `memory`");
+    if let Err(err) = memory.read(&mut caller, buf as usize, &mut value) {
+        debug!("Failed to read wasm memory: {}", err);
+        return -EFAULT;
+    }
+    let (var_ptr, var_size) = match global_var_pointer(&mut caller, program, &var_name, true) {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("No writable global variable named `{}`", var_name);
+            return e;
+        }
+    };
+    if size as usize > var_size {
+        debug!(
+            "`{}` has only {} bytes, {} provided",
+            var_name, var_size, size
+        );
+        return -EINVAL;
+    }
+    // SAFETY: the pointer is checked to hold at least `size` bytes
+    unsafe { std::ptr::copy_nonoverlapping(value.as_ptr(), var_ptr, value.len()) };
+    0
+}
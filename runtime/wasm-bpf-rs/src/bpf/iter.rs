@@ -0,0 +1,104 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::{fs::File, io::Read, os::fd::FromRawFd};
+
+use libbpf_rs::libbpf_sys::bpf_iter_create;
+use log::debug;
+
+use crate::{ensure_enough_memory, state::CallerType, utils::CallerUtils};
+
+use super::{attach::Attachment, WasmPointer, EBADF, EFAULT, EINVAL, EIO};
+
+/// Create an iterator from the link of an iterator program.
+/// Each iterator walks the kernel objects once, and its output is read from the file
+pub(crate) fn create_iter(link_fd: i32) -> Result<File, i32> {
+    // SAFETY: no pointers are passed
+    let fd = unsafe { bpf_iter_create(link_fd) };
+    if fd < 0 {
+        debug!("Failed to create iterator: {}", fd);
+        return Err(fd);
+    }
+    // SAFETY: the fd is just created, and is owned by nobody else
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// create an iterator from a link of an iterator program, like `SEC("iter/task")`,
+/// which is returned by `wasm_bpf_program_attach`.
+///
+/// Returns a positive handle for `wasm_bpf_iter_read`, or a negative value if failed
+pub fn wasm_bpf_iter_create(mut caller: CallerType, link: i32) -> i32 {
+    debug!("Create iterator from link {}", link);
+    let state = caller.data_mut();
+    let (object, link_fd) = match state.object_map.values_mut().find_map(|v| {
+        let link_fd = match v.links.get(&link)? {
+            Attachment::Link(link) => Some(link.fd()),
+            _ => None,
+        };
+        Some((v, link_fd))
+    }) {
+        Some((object, Some(link_fd))) => (object, link_fd),
+        Some((_, None)) => {
+            debug!("Link {} isn't a bpf link", link);
+            return -EINVAL;
+        }
+        None => {
+            debug!("Invalid link handle: {}", link);
+            return -EBADF;
+        }
+    };
+    let iter = match create_iter(link_fd) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    // Iterators share the handles with links
+    let iter_id = state.next_link_id;
+    state.next_link_id += 1;
+    object.iters.insert(iter_id, iter);
+    iter_id
+}
+
+/// read the next chunk of the output of an iterator into `buf`.
+///
+/// Returns the number of bytes read, 0 if the iteration is done, or a negative value if failed
+pub fn wasm_bpf_iter_read(mut caller: CallerType, iter: i32, buf: WasmPointer, size: u32) -> i32 {
+    ensure_enough_memory!(caller, buf, size, -EFAULT);
+    let mut chunk = vec![0u8; size as usize];
+    let result = caller
+        .data_mut()
+        .object_map
+        .values_mut()
+        .find_map(|v| v.iters.get_mut(&iter))
+        .map(|v| v.read(&mut chunk));
+    let read_size = match result {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            debug!("Failed to read iterator {}: {}", iter, e);
+            return -e.raw_os_error().unwrap_or(EIO);
+        }
+        None => {
+            debug!("Invalid iterator handle: {}", iter);
+            return -EBADF;
+        }
+    };
+    let memory = caller.get_memory().expect("Expected exported `memory`");
+    if let Err(e) = memory.write(&mut caller, buf as usize, &chunk[..read_size]) {
+        debug!("Failed to write wasm memory: {}", e);
+        return -EFAULT;
+    }
+    read_size as i32
+}
+
+/// close an iterator created by `wasm_bpf_iter_create`
+pub fn wasm_bpf_iter_close(mut caller: CallerType, iter: i32) -> i32 {
+    debug!("Close iterator: {}", iter);
+    for object in caller.data_mut().object_map.values_mut() {
+        if object.iters.remove(&iter).is_some() {
+            return 0;
+        }
+    }
+    debug!("Invalid iterator handle: {}", iter);
+    -EBADF
+}
@@ -0,0 +1,91 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::{
+    cell::RefCell,
+    sync::{Once, OnceLock},
+};
+
+use libbpf_rs::{get_print, set_print, PrintCallback, PrintLevel};
+use log::debug;
+
+use crate::{ensure_enough_memory, state::CallerType, utils::CallerUtils};
+
+use super::{WasmPointer, EFAULT};
+
+thread_local! {
+    /// The libbpf output being captured on this thread, and the lowest level to capture
+    static CAPTURED_LOG: RefCell<Option<(PrintLevel, String)>> = const { RefCell::new(None) };
+}
+
+static INSTALL_PRINT: Once = Once::new();
+/// The print callback before ours, which the output is still passed to
+static PREVIOUS_PRINT: OnceLock<Option<(PrintLevel, PrintCallback)>> = OnceLock::new();
+
+fn capture_print(level: PrintLevel, msg: String) {
+    CAPTURED_LOG.with(|v| {
+        if let Some((min_level, log)) = &mut *v.borrow_mut() {
+            if level <= *min_level {
+                log.push_str(&msg);
+            }
+        }
+    });
+    if let Some(Some((min_level, previous))) = PREVIOUS_PRINT.get() {
+        if level <= *min_level {
+            previous(level, msg);
+        }
+    }
+}
+
+/// Run `f`, and return the libbpf output at `level` or more severe printed on this thread meanwhile.
+///
+/// The print callback of libbpf is replaced at the first call, passing the output to the previous one
+pub(crate) fn capture_libbpf_log<T>(level: PrintLevel, f: impl FnOnce() -> T) -> (T, String) {
+    INSTALL_PRINT.call_once(|| {
+        let _ = PREVIOUS_PRINT.set(get_print());
+        set_print(Some((PrintLevel::Debug, capture_print)));
+    });
+    CAPTURED_LOG.with(|v| *v.borrow_mut() = Some((level, String::new())));
+    let result = f();
+    let log = CAPTURED_LOG.with(|v| v.borrow_mut().take().map(|v| v.1).unwrap_or_default());
+    (result, log)
+}
+
+/// get why the last attempt of opening or loading a bpf object failed, including the libbpf
+/// output and the verifier log. It's written into `buf` with a terminating null, truncated if
+/// needed.
+///
+/// Returns the length of the whole message, or 0 if the last attempt succeeded
+pub fn wasm_bpf_last_error(mut caller: CallerType, buf: WasmPointer, size: u32) -> i32 {
+    let message = match &caller.data().last_load_error {
+        Some(v) => v.to_string(),
+        None => return 0,
+    };
+    if size > 0 {
+        ensure_enough_memory!(caller, buf, size, -EFAULT);
+        let copied = message.len().min(size as usize - 1);
+        let mut data = message.as_bytes()[..copied].to_vec();
+        data.push(0);
+        let memory = caller.get_memory().expect("Expected exported `memory`");
+        if let Err(err) = memory.write(&mut caller, buf as usize, &data) {
+            debug!("Failed to write wasm memory: {}", err);
+            return -EFAULT;
+        }
+    }
+    message.len() as i32
+}
+
+/// get the negative errno of the last attempt of opening or loading a bpf object,
+/// since `wasm_load_bpf_object` and `wasm_open_bpf_object` return only 0 if failed.
+///
+/// Returns 0 if the last attempt succeeded
+pub fn wasm_bpf_last_errno(caller: CallerType) -> i32 {
+    caller
+        .data()
+        .last_load_error
+        .as_ref()
+        .map(|v| v.errno)
+        .unwrap_or(0)
+}
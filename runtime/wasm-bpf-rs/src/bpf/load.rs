@@ -3,65 +3,197 @@
 //! Copyright (c) 2023, eunomia-bpf
 //! All rights reserved.
 //!
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, ffi::c_void, ptr::NonNull, rc::Rc};
 
-use libbpf_rs::ObjectBuilder;
+use libbpf_rs::{
+    libbpf_sys::{bpf_object__open_mem, bpf_object_open_opts, size_t},
+    OpenObject,
+};
 use log::debug;
 
 use crate::{
-    state::{CallerType, WrapperObject},
+    error::BpfLoadError,
+    state::{AppState, CallerType, WrapperObject, WrapperOpenObject},
     utils::CallerUtils,
 };
 
-use super::WasmPointer;
+use super::{
+    libbpf_errno, libbpf_log::capture_libbpf_log, struct_ops::attach_all_struct_ops, BpfObjectType,
+    WasmPointer, EBADF, EFAULT, EIO,
+};
 
-/// load a bpf object from memory into the kernel
-pub fn wasm_load_bpf_object(
-    mut caller: CallerType,
+/// Open a bpf object from the guest memory, without loading it into the kernel.
+/// The error of the last attempt is cleared first
+fn open_bpf_object_from_guest(
+    caller: &mut CallerType,
     obj_buf: WasmPointer,
     obj_buf_size: u32,
-) -> u64 {
-    debug!("Load bpf object caller");
+) -> Result<WrapperOpenObject, i32> {
+    caller.data_mut().last_load_error = None;
     let memory = caller.get_memory().expect("Expected exported `memory`");
-    let mut buf = vec![0u8];
+    let mut buf = [0u8];
     if let Err(err) = memory.read(
-        &mut caller,
-        obj_buf as usize + obj_buf_size as usize - 1,
+        &mut *caller,
+        (obj_buf as usize + obj_buf_size as usize).saturating_sub(1),
         &mut buf[..],
     ) {
         debug!(
             "Invalid pointer passed from wasm guest {}, size={}, err={}",
             obj_buf, obj_buf_size, err
         );
-        return 0;
+        caller.data_mut().last_load_error = Some(BpfLoadError {
+            message: format!("Invalid bpf object buffer: {}", err),
+            errno: -EFAULT,
+            log: String::new(),
+        });
+        return Err(-EFAULT);
     }
-    let open_object = match ObjectBuilder::default().open_memory(
-        "",
-        &memory.data(&mut caller)[obj_buf as usize..(obj_buf + obj_buf_size) as usize],
-    ) {
-        Ok(v) => v,
+    let log_level = caller.data().libbpf_log_level;
+    let (result, log) = capture_libbpf_log(log_level, || {
+        let mem =
+            &memory.data(&mut *caller)[obj_buf as usize..obj_buf as usize + obj_buf_size as usize];
+        let opts = bpf_object_open_opts {
+            sz: std::mem::size_of::<bpf_object_open_opts>() as size_t,
+            ..Default::default()
+        };
+        // libbpf-rs only gives out the pointer by consuming the `OpenObject`, so the object is
+        // opened through libbpf directly to keep the pointer.
+        // SAFETY: the buffer and the options live through the call, and libbpf copies the buffer
+        let ptr = unsafe {
+            bpf_object__open_mem(mem.as_ptr() as *const c_void, mem.len() as size_t, &opts)
+        };
+        let ptr = NonNull::new(ptr).ok_or_else(|| match errno::errno().0 {
+            0 => libbpf_rs::Error::System(EIO),
+            v => libbpf_rs::Error::System(v),
+        })?;
+        // SAFETY: the pointer is just opened, and owned by nobody else
+        unsafe { OpenObject::from_ptr(ptr) }.map(|object| WrapperOpenObject { object, ptr })
+    });
+    result.map_err(|err| {
+        debug!("Failed to open bpf object: {}", err);
+        caller.data_mut().last_load_error = Some(BpfLoadError {
+            message: format!("Failed to open bpf object: {}", err),
+            errno: libbpf_errno(&err),
+            log,
+        });
+        libbpf_errno(&err)
+    })
+}
+
+/// Load an opened bpf object into the kernel, and wrap it.
+/// The libbpf output is kept in the state if it fails
+fn load_opened_object(state: &mut AppState, open_object: OpenObject) -> Result<WrapperObject, i32> {
+    let (result, log) = capture_libbpf_log(state.libbpf_log_level, || open_object.load());
+    match result {
+        Ok(object) => Ok(WrapperObject {
+            object: Rc::new(RefCell::new(object)),
+            poll_buffers: HashMap::default(),
+            datasec_mmaps: HashMap::default(),
+            links: HashMap::default(),
+            iters: HashMap::default(),
+        }),
         Err(err) => {
-            debug!("Failed to open bpf object: {}", err);
-            return 0;
+            debug!("Failed to load bpf object: {}", err);
+            state.last_load_error = Some(BpfLoadError {
+                message: format!("Failed to load bpf object: {}", err),
+                errno: libbpf_errno(&err),
+                log,
+            });
+            Err(libbpf_errno(&err))
         }
+    }
+}
+
+/// Put a loaded object into the state, and attach its struct_ops maps if configured.
+/// The object is dropped if the struct_ops maps failed to be attached
+fn insert_loaded_object(
+    state: &mut AppState,
+    id: BpfObjectType,
+    object: WrapperObject,
+) -> Result<(), i32> {
+    state.object_map.insert(id, object);
+    if !state.auto_attach_struct_ops {
+        return Ok(());
+    }
+    let (result, log) =
+        capture_libbpf_log(state.libbpf_log_level, || attach_all_struct_ops(state, id));
+    if let Err(e) = result {
+        state.object_map.remove(&id);
+        state.last_load_error = Some(BpfLoadError {
+            message: format!("Failed to attach struct_ops maps: {}", e),
+            errno: e,
+            log,
+        });
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// load a bpf object from memory into the kernel
+pub fn wasm_load_bpf_object(
+    mut caller: CallerType,
+    obj_buf: WasmPointer,
+    obj_buf_size: u32,
+) -> u64 {
+    debug!("Load bpf object caller");
+    let open_object = match open_bpf_object_from_guest(&mut caller, obj_buf, obj_buf_size) {
+        Ok(v) => v,
+        Err(_) => return 0,
     };
-    let object = match open_object.load() {
+    let state = caller.data_mut();
+    let object = match load_opened_object(state, open_object.object) {
         Ok(v) => v,
-        Err(err) => {
-            debug!("Failed to load bpf object: {}", err);
-            return 0;
-        }
+        Err(_) => return 0,
     };
-    let mut state = caller.data_mut();
     let next_id = state.next_object_id;
     state.next_object_id += 1;
-    state.object_map.insert(
-        next_id,
-        WrapperObject {
-            object: Rc::new(RefCell::new(object)),
-            poll_buffer: None,
-        },
-    );
+    if insert_loaded_object(state, next_id, object).is_err() {
+        return 0;
+    }
     debug!("Load bpf object done, id={}", next_id);
     next_id
 }
+
+/// open a bpf object from memory, but don't load it into the kernel.
+/// The object can be configured before calling `wasm_bpf_object_load`
+pub fn wasm_open_bpf_object(
+    mut caller: CallerType,
+    obj_buf: WasmPointer,
+    obj_buf_size: u32,
+) -> u64 {
+    debug!("Open bpf object caller");
+    let open_object = match open_bpf_object_from_guest(&mut caller, obj_buf, obj_buf_size) {
+        Ok(v) => v,
+        Err(_) => return 0,
+    };
+    let state = caller.data_mut();
+    let next_id = state.next_object_id;
+    state.next_object_id += 1;
+    state.open_object_map.insert(next_id, open_object);
+    debug!("Open bpf object done, id={}", next_id);
+    next_id
+}
+
+/// load a bpf object opened by `wasm_open_bpf_object` into the kernel.
+/// The object keeps its id after being loaded
+pub fn wasm_bpf_object_load(mut caller: CallerType, program: BpfObjectType) -> i32 {
+    debug!("Load opened bpf object: {}", program);
+    let open_object = match caller.data_mut().open_object_map.remove(&program) {
+        Some(v) => v,
+        None => {
+            debug!("No opened bpf object with id {}", program);
+            return -EBADF;
+        }
+    };
+    let state = caller.data_mut();
+    state.last_load_error = None;
+    let object = match load_opened_object(state, open_object.object) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    if let Err(e) = insert_loaded_object(state, program, object) {
+        return e;
+    }
+    debug!("Load opened bpf object done, id={}", program);
+    0
+}
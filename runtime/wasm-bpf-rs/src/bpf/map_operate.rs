@@ -7,19 +7,340 @@ use std::os::fd::BorrowedFd;
 
 use libbpf_rs::{
     libbpf_sys::{
-        bpf_map_delete_elem_flags, bpf_map_get_next_key, bpf_map_lookup_elem_flags,
-        bpf_map_update_elem, BPF_MAP_DELETE_ELEM, BPF_MAP_GET_NEXT_KEY, BPF_MAP_LOOKUP_ELEM,
-        BPF_MAP_UPDATE_ELEM,
+        bpf_map_batch_opts, bpf_map_delete_batch, bpf_map_delete_elem_flags, bpf_map_get_next_key,
+        bpf_map_lookup_and_delete_batch, bpf_map_lookup_batch, bpf_map_lookup_elem_flags,
+        bpf_map_update_batch, bpf_map_update_elem, libbpf_num_possible_cpus, BPF_MAP_DELETE_BATCH,
+        BPF_MAP_DELETE_ELEM, BPF_MAP_GET_NEXT_KEY, BPF_MAP_LOOKUP_AND_DELETE_BATCH,
+        BPF_MAP_LOOKUP_BATCH, BPF_MAP_LOOKUP_ELEM, BPF_MAP_TYPE_LRU_PERCPU_HASH,
+        BPF_MAP_TYPE_PERCPU_ARRAY, BPF_MAP_TYPE_PERCPU_CGROUP_STORAGE, BPF_MAP_TYPE_PERCPU_HASH,
+        BPF_MAP_UPDATE_BATCH, BPF_MAP_UPDATE_ELEM,
     },
     MapInfo,
 };
 use log::{debug, error};
 
-use crate::{bpf::EINVAL, ensure_enough_memory, state::CallerType, utils::CallerUtils};
+use crate::{ensure_enough_memory, state::CallerType, utils::CallerUtils};
 
-use super::WasmPointer;
+use super::{libbpf_errno, WasmPointer, EBADF, EFAULT, EINVAL, ENOENT};
 
-/// map operate, used for map update, lookup, delete, get_next_key
+/// The error of maps without batch operations, which isn't exported to user space
+const ENOTSUPP: i32 = 524;
+
+/// The size of `struct wasm_bpf_map_batch`
+const MAP_BATCH_SIZE: usize = 32;
+/// The offset of `count` in `struct wasm_bpf_map_batch`
+const MAP_BATCH_COUNT_OFFSET: usize = 16;
+
+/// The arguments of a batch operation, read from `struct wasm_bpf_map_batch`:
+/// ```c
+/// struct wasm_bpf_map_batch {
+///     uint32_t in_batch;  // the cursor to continue from; null to start from the first element
+///     uint32_t out_batch; // receives the cursor of the next batch
+///     uint32_t keys;
+///     uint32_t values;
+///     uint32_t count;     // the number of elements of keys and values; receives the number processed
+///     uint32_t reserved;
+///     uint64_t elem_flags;
+/// };
+/// ```
+#[derive(Debug)]
+struct MapBatch {
+    in_batch: WasmPointer,
+    out_batch: WasmPointer,
+    keys: WasmPointer,
+    values: WasmPointer,
+    count: u32,
+    elem_flags: u64,
+}
+
+impl MapBatch {
+    fn from_bytes(buf: &[u8; MAP_BATCH_SIZE]) -> Self {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        Self {
+            in_batch: u32_at(0),
+            out_batch: u32_at(4),
+            keys: u32_at(8),
+            values: u32_at(12),
+            count: u32_at(MAP_BATCH_COUNT_OFFSET),
+            elem_flags: u64::from_le_bytes(buf[24..32].try_into().unwrap()),
+        }
+    }
+}
+
+/// The size of the values of a map in user space. Values of per-cpu maps
+/// hold one slot for each possible cpu, each one aligned to 8 bytes
+fn user_value_size(map_type: u32, value_size: usize) -> Result<usize, i32> {
+    match map_type {
+        BPF_MAP_TYPE_PERCPU_HASH
+        | BPF_MAP_TYPE_PERCPU_ARRAY
+        | BPF_MAP_TYPE_LRU_PERCPU_HASH
+        | BPF_MAP_TYPE_PERCPU_CGROUP_STORAGE => {
+            // SAFETY: it only reads the possible cpus of the system
+            let cpus = unsafe { libbpf_num_possible_cpus() };
+            if cpus <= 0 {
+                debug!("Failed to get the number of possible cpus: {}", cpus);
+                return Err(if cpus < 0 { cpus } else { -EINVAL });
+            }
+            Ok(value_size.div_ceil(8) * 8 * cpus as usize)
+        }
+        _ => Ok(value_size),
+    }
+}
+
+/// Look up `count` elements starting after the key `in_batch` one by one, like
+/// `BPF_MAP_LOOKUP_BATCH` (or `BPF_MAP_LOOKUP_AND_DELETE_BATCH` if `delete`),
+/// for maps without batch operations. The cursors are the last keys processed.
+///
+/// `count` receives the number of elements processed. Returns `-ENOENT` if the
+/// map has no more elements, like the batch commands.
+///
+/// # Safety
+/// `in_batch` must be null or hold a key, `out_batch` must hold a key,
+/// and `keys` and `values` must hold `count` keys and values
+#[allow(clippy::too_many_arguments)]
+pub(crate) unsafe fn lookup_batch_fallback(
+    fd: i32,
+    in_batch: *const u8,
+    out_batch: *mut u8,
+    keys: *mut u8,
+    values: *mut u8,
+    count: &mut u32,
+    elem_flags: u64,
+    delete: bool,
+    key_size: usize,
+    value_size: usize,
+) -> i32 {
+    let max_count = std::mem::take(count) as usize;
+    let mut prev_key = (!in_batch.is_null()).then_some(in_batch);
+    let mut processed = 0;
+    let mut ret = 0;
+    while processed < max_count {
+        let key = keys.add(processed * key_size);
+        let next = bpf_map_get_next_key(
+            fd,
+            prev_key.unwrap_or(std::ptr::null()) as *const _,
+            key as *mut _,
+        );
+        if next != 0 {
+            ret = next;
+            break;
+        }
+        prev_key = Some(key);
+        let value = values.add(processed * value_size);
+        match bpf_map_lookup_elem_flags(fd, key as *const _, value as *mut _, elem_flags) {
+            0 => processed += 1,
+            // The element was deleted after its key was got. Its slot is reused by the next one
+            v if v == -ENOENT => {
+                // Keep the key out of the slot, since the next key overwrites it
+                std::ptr::copy_nonoverlapping(key, out_batch, key_size);
+                prev_key = Some(out_batch);
+            }
+            v => {
+                ret = v;
+                break;
+            }
+        }
+    }
+    if let Some(key) = prev_key {
+        std::ptr::copy(key, out_batch, key_size);
+    }
+    if delete {
+        for i in 0..processed {
+            let key = keys.add(i * key_size);
+            let v = bpf_map_delete_elem_flags(fd, key as *const _, elem_flags);
+            if v != 0 && v != -ENOENT {
+                *count = i as u32;
+                return v;
+            }
+        }
+    }
+    *count = processed as u32;
+    ret
+}
+
+/// Update (or delete if `values` is null) `count` elements one by one, like
+/// `BPF_MAP_UPDATE_BATCH` and `BPF_MAP_DELETE_BATCH`, for maps without batch operations.
+///
+/// `count` receives the number of elements processed before the first failure
+///
+/// # Safety
+/// `keys` must hold `count` keys, and `values` must be null or hold `count` values
+pub(crate) unsafe fn update_batch_fallback(
+    fd: i32,
+    keys: *const u8,
+    values: *const u8,
+    count: &mut u32,
+    elem_flags: u64,
+    key_size: usize,
+    value_size: usize,
+) -> i32 {
+    for i in 0..*count as usize {
+        let key = keys.add(i * key_size) as *const _;
+        let ret = if values.is_null() {
+            bpf_map_delete_elem_flags(fd, key, elem_flags)
+        } else {
+            bpf_map_update_elem(fd, key, values.add(i * value_size) as *const _, elem_flags)
+        };
+        if ret != 0 {
+            *count = i as u32;
+            return ret;
+        }
+    }
+    0
+}
+
+/// Run a batch command with the arguments in `struct wasm_bpf_map_batch` at `batch_ptr`,
+/// falling back to operating the elements one by one if the map doesn't support it
+#[allow(clippy::too_many_arguments)]
+fn map_operate_batch(
+    caller: &mut CallerType,
+    fd: i32,
+    cmd: u32,
+    batch_ptr: WasmPointer,
+    flags: u64,
+    key_size: usize,
+    value_size: usize,
+) -> i32 {
+    ensure_enough_memory!(*caller, batch_ptr, MAP_BATCH_SIZE, -EFAULT);
+    let memory = caller.get_memory().expect("Expected exported `memory`");
+    let mut buf = [0u8; MAP_BATCH_SIZE];
+    if let Err(err) = memory.read(&mut *caller, batch_ptr as usize, &mut buf) {
+        debug!("Failed to read wasm memory: {}", err);
+        return -EFAULT;
+    }
+    let batch = MapBatch::from_bytes(&buf);
+    debug!("map batch operate: {:?}", batch);
+    let (Some(keys_size), Some(values_size)) = (
+        key_size.checked_mul(batch.count as usize),
+        value_size.checked_mul(batch.count as usize),
+    ) else {
+        debug!("Too many elements: {}", batch.count);
+        return -EINVAL;
+    };
+    let is_lookup = matches!(cmd, BPF_MAP_LOOKUP_BATCH | BPF_MAP_LOOKUP_AND_DELETE_BATCH);
+    if is_lookup {
+        // The kernel takes at least 4 bytes for the cursors of hash maps
+        let cursor_size = key_size.max(4);
+        if batch.in_batch != 0 {
+            ensure_enough_memory!(*caller, batch.in_batch, cursor_size, -EFAULT);
+        }
+        if batch.out_batch == 0 {
+            debug!("No out_batch provided");
+            return -EINVAL;
+        }
+        ensure_enough_memory!(*caller, batch.out_batch, cursor_size, -EFAULT);
+    }
+    ensure_enough_memory!(*caller, batch.keys, keys_size, -EFAULT);
+    if cmd != BPF_MAP_DELETE_BATCH {
+        ensure_enough_memory!(*caller, batch.values, values_size, -EFAULT);
+    }
+    // SAFETY: memory addresses are checked to be valid
+    let pointer = |caller: &mut CallerType, v: WasmPointer| unsafe {
+        caller.raw_pointer_at_unchecked(v as usize) as *mut u8
+    };
+    let in_batch = if batch.in_batch == 0 {
+        std::ptr::null_mut()
+    } else {
+        pointer(caller, batch.in_batch)
+    };
+    let out_batch = pointer(caller, batch.out_batch);
+    let keys = pointer(caller, batch.keys);
+    let values = pointer(caller, batch.values);
+    let opts = bpf_map_batch_opts {
+        sz: std::mem::size_of::<bpf_map_batch_opts>() as _,
+        elem_flags: batch.elem_flags,
+        flags,
+    };
+    let mut count = batch.count;
+    // SAFETY: memory addresses are checked to be valid
+    let mut ret = unsafe {
+        match cmd {
+            BPF_MAP_LOOKUP_BATCH => bpf_map_lookup_batch(
+                fd,
+                in_batch as *mut _,
+                out_batch as *mut _,
+                keys as *mut _,
+                values as *mut _,
+                &mut count,
+                &opts,
+            ),
+            BPF_MAP_LOOKUP_AND_DELETE_BATCH => bpf_map_lookup_and_delete_batch(
+                fd,
+                in_batch as *mut _,
+                out_batch as *mut _,
+                keys as *mut _,
+                values as *mut _,
+                &mut count,
+                &opts,
+            ),
+            BPF_MAP_UPDATE_BATCH => {
+                bpf_map_update_batch(fd, keys as *const _, values as *const _, &mut count, &opts)
+            }
+            _ => bpf_map_delete_batch(fd, keys as *const _, &mut count, &opts),
+        }
+    };
+    // Some maps don't implement the batch commands. Other errors, like EINVAL for bad flags,
+    // are returned as is, and elements already processed by the kernel are never processed again
+    if (ret == -ENOTSUPP || ret == -libc::EOPNOTSUPP) && count == 0 {
+        debug!(
+            "map batch operate failed with {}, operate elements one by one",
+            ret
+        );
+        count = batch.count;
+        // SAFETY: memory addresses are checked to be valid
+        ret = unsafe {
+            match cmd {
+                BPF_MAP_LOOKUP_BATCH | BPF_MAP_LOOKUP_AND_DELETE_BATCH => lookup_batch_fallback(
+                    fd,
+                    in_batch,
+                    out_batch,
+                    keys,
+                    values,
+                    &mut count,
+                    batch.elem_flags,
+                    cmd == BPF_MAP_LOOKUP_AND_DELETE_BATCH,
+                    key_size,
+                    value_size,
+                ),
+                BPF_MAP_UPDATE_BATCH => update_batch_fallback(
+                    fd,
+                    keys,
+                    values,
+                    &mut count,
+                    batch.elem_flags,
+                    key_size,
+                    value_size,
+                ),
+                _ => update_batch_fallback(
+                    fd,
+                    keys,
+                    std::ptr::null(),
+                    &mut count,
+                    batch.elem_flags,
+                    key_size,
+                    value_size,
+                ),
+            }
+        };
+    }
+    // The number of elements processed is written back even if failed, like the kernel does
+    if let Err(err) = memory.write(
+        &mut *caller,
+        batch_ptr as usize + MAP_BATCH_COUNT_OFFSET,
+        &count.to_le_bytes(),
+    ) {
+        debug!("Failed to write wasm memory: {}", err);
+        return -EFAULT;
+    }
+    if ret != 0 {
+        debug!("map batch operate failed with {}", ret);
+    }
+    ret
+}
+
+/// map operate, used for map update, lookup, delete, get_next_key,
+/// and the batch versions of them, whose arguments `key` points to
 pub fn wasm_bpf_map_operate(
     mut caller: CallerType,
     fd: i32,
@@ -33,25 +354,30 @@ pub fn wasm_bpf_map_operate(
         "map operate: fd: {}, cmd: {}, key: {}, value: {}, next_key: {}, flags: {}",
         fd, cmd, key, value, next_key, flags
     );
-    let (key_size, value_size) = {
+    if fd < 0 {
+        debug!("Invalid map fd: {}", fd);
+        return -EBADF;
+    }
+    let (key_size, value_size, map_type) = {
         // SAFETY: The fd is only used to query map info, which will not be used to write or read
         let map_info = match MapInfo::new(unsafe { BorrowedFd::borrow_raw(fd) }) {
             Ok(v) => v,
             Err(err) => {
                 error!("Failed to get MapInfo: {}", err);
-                return -1;
+                return libbpf_errno(&err);
             }
         };
         (
             map_info.info.key_size as usize,
             map_info.info.value_size as usize,
+            map_info.info.type_,
         )
     };
 
     match cmd as u32 {
         BPF_MAP_GET_NEXT_KEY => {
-            ensure_enough_memory!(caller, key, key_size, EINVAL);
-            ensure_enough_memory!(caller, next_key, key_size, EINVAL);
+            ensure_enough_memory!(caller, key, key_size, -EFAULT);
+            ensure_enough_memory!(caller, next_key, key_size, -EFAULT);
             // SAFETY: memory addresses are checked to be valid
             let ret_val = unsafe {
                 bpf_map_get_next_key(
@@ -66,8 +392,8 @@ pub fn wasm_bpf_map_operate(
             }
         }
         BPF_MAP_LOOKUP_ELEM => {
-            ensure_enough_memory!(caller, key, key_size, EINVAL);
-            ensure_enough_memory!(caller, value, value_size, EINVAL);
+            ensure_enough_memory!(caller, key, key_size, -EFAULT);
+            ensure_enough_memory!(caller, value, value_size, -EFAULT);
             // SAFETY: memory addresses are checked to be valid
             let ret_val = unsafe {
                 bpf_map_lookup_elem_flags(
@@ -83,8 +409,8 @@ pub fn wasm_bpf_map_operate(
             }
         }
         BPF_MAP_UPDATE_ELEM => {
-            ensure_enough_memory!(caller, key, key_size, EINVAL);
-            ensure_enough_memory!(caller, value, value_size, EINVAL);
+            ensure_enough_memory!(caller, key, key_size, -EFAULT);
+            ensure_enough_memory!(caller, value, value_size, -EFAULT);
             // SAFETY: memory addresses are checked to be valid
             let ret_val = unsafe {
                 bpf_map_update_elem(
@@ -100,7 +426,7 @@ pub fn wasm_bpf_map_operate(
             }
         }
         BPF_MAP_DELETE_ELEM => {
-            ensure_enough_memory!(caller, key, key_size, EINVAL);
+            ensure_enough_memory!(caller, key, key_size, -EFAULT);
             // SAFETY: memory addresses are checked to be valid
             let ret_val = unsafe {
                 bpf_map_delete_elem_flags(
@@ -114,6 +440,24 @@ pub fn wasm_bpf_map_operate(
                 return ret_val;
             }
         }
+        BPF_MAP_LOOKUP_BATCH
+        | BPF_MAP_LOOKUP_AND_DELETE_BATCH
+        | BPF_MAP_UPDATE_BATCH
+        | BPF_MAP_DELETE_BATCH => {
+            let value_size = match user_value_size(map_type, value_size) {
+                Ok(v) => v,
+                Err(e) => return e,
+            };
+            return map_operate_batch(
+                &mut caller,
+                fd,
+                cmd as u32,
+                key,
+                flags,
+                key_size,
+                value_size,
+            );
+        }
         // More syscall commands can be allowed here
         s => {
             debug!("Map operation `{}` currently not supported", s);
@@ -3,15 +3,38 @@
 //! Copyright (c) 2023, eunomia-bpf
 //! All rights reserved.
 //!
-pub(crate) const EINVAL: i32 = 22;
+//! The host functions return 0 or a positive value if succeeded, or a negative errno if failed:
+//! - `-EBADF` for invalid handles of objects, links and iterators, or invalid fds
+//! - `-ENOENT` for programs, maps and other things not found by name
+//! - `-EFAULT` for pointers out of the guest memory, or strings not terminated
+//! - `-EINVAL` for invalid arguments
+//! - the errno of the kernel or libbpf for other failures
 pub(crate) const ENOENT: i32 = 2;
+pub(crate) const EIO: i32 = 5;
+pub(crate) const EBADF: i32 = 9;
+pub(crate) const EFAULT: i32 = 14;
+pub(crate) const EEXIST: i32 = 17;
+pub(crate) const EINVAL: i32 = 22;
 
+pub(crate) mod abi;
 pub(crate) mod attach;
+pub(crate) mod btf_json;
 pub(crate) mod close;
+pub(crate) mod configure;
 pub(crate) mod fd_by_name;
+pub(crate) mod global_var;
+pub(crate) mod iter;
+pub(crate) mod libbpf_log;
 pub(crate) mod load;
 pub(crate) mod map_operate;
+pub(crate) mod object_info;
+pub(crate) mod perf_event;
 pub(crate) mod poll;
+pub(crate) mod socket_filter;
+pub(crate) mod struct_ops;
+pub(crate) mod symbolize;
+pub(crate) mod tc;
+pub(crate) mod uprobe;
 pub(crate) mod wrapper_poll;
 
 #[macro_export]
@@ -21,7 +44,7 @@ macro_rules! ensure_program_mut_by_state {
             Some(v) => v,
             None => {
                 log::debug!("Invalid program: {}", $program);
-                return -1;
+                return (-$crate::bpf::EBADF).into();
             }
         }
     };
@@ -34,7 +57,20 @@ macro_rules! ensure_program_by_state {
             Some(v) => v,
             None => {
                 log::debug!("Invalid program: {}", $program);
-                return -1;
+                return (-$crate::bpf::EBADF).into();
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! ensure_open_object_mut_by_state {
+    ($state: expr, $program: expr) => {
+        match $state.open_object_map.get_mut(&$program) {
+            Some(v) => &mut v.object,
+            None => {
+                log::debug!("Invalid opened object: {}", $program);
+                return (-$crate::bpf::EBADF).into();
             }
         }
     };
@@ -64,11 +100,28 @@ macro_rules! ensure_c_str {
             Ok(v) => v.to_string(),
             Err(err) => {
                 log::debug!("Failed to read `{}`: {}", stringify!($var_name), err);
-                return -1;
+                return (-$crate::bpf::EFAULT).into();
             }
         }
     }};
 }
+/// Convert an error of libbpf-rs into a negative errno
+pub(crate) fn libbpf_errno(err: &libbpf_rs::Error) -> i32 {
+    match err {
+        libbpf_rs::Error::System(v) => -v.abs(),
+        libbpf_rs::Error::InvalidInput(_) => -EINVAL,
+        libbpf_rs::Error::Internal(_) => -EIO,
+    }
+}
+
+/// The negative errno of the last failed system call
+pub(crate) fn last_errno() -> i32 {
+    match errno::errno().0 {
+        0 => -EIO,
+        v => -v,
+    }
+}
+
 /// The pointer type in 32bit wasm
 pub type WasmPointer = u32;
 /// The handle to a bpf object
@@ -81,18 +134,21 @@ macro_rules! ensure_enough_memory {
     ($caller: expr, $pointer:expr, $size: expr, $return_val: expr) => {{
         use $crate::utils::CallerUtils;
         let mut buf = vec![0u8];
-        match $caller
-            .get_memory()
-            .expect("Expected exported memory!")
-            .read(
-                &mut $caller,
-                $pointer as usize + $size as usize - 1,
-                &mut buf,
-            ) {
-            Ok(_) => {}
-            Err(err) => {
-                debug!("Invalid pointer for {}: {}", stringify!($pointer), err);
-                return $return_val;
+        // Empty buffers are always valid
+        if $size as usize > 0 {
+            match $caller
+                .get_memory()
+                .expect("Expected exported memory!")
+                .read(
+                    &mut $caller,
+                    ($pointer as usize).saturating_add($size as usize - 1),
+                    &mut buf,
+                ) {
+                Ok(_) => {}
+                Err(err) => {
+                    debug!("Invalid pointer for {}: {}", stringify!($pointer), err);
+                    return $return_val;
+                }
             }
         }
     }};
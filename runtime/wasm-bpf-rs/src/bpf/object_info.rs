@@ -0,0 +1,201 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::{ffi::CStr, os::raw::c_char, ptr::NonNull};
+
+use libbpf_rs::libbpf_sys::{
+    bpf_map, bpf_map__fd, bpf_map__key_size, bpf_map__map_flags, bpf_map__max_entries,
+    bpf_map__name, bpf_map__type, bpf_map__value_size, bpf_obj_get_info_by_fd, bpf_object,
+    bpf_object__next_map, bpf_object__next_program, bpf_prog_info, bpf_program, bpf_program__fd,
+    bpf_program__name, bpf_program__section_name, bpf_program__type,
+};
+use log::debug;
+
+use crate::{
+    ensure_enough_memory, ensure_program_by_caller, state::CallerType, utils::CallerUtils,
+};
+
+use super::{BpfObjectType, WasmPointer, EFAULT, EINVAL};
+
+/// The size of the name fields, including the terminating null
+const NAME_SIZE: usize = 64;
+
+/// Append a string as a field of `size` bytes, truncated with a terminating null
+fn push_str_field(entry: &mut Vec<u8>, value: *const c_char, size: usize) {
+    let bytes = if value.is_null() {
+        &[][..]
+    } else {
+        // SAFETY: libbpf returns null-terminated strings owned by the object
+        unsafe { CStr::from_ptr(value) }.to_bytes()
+    };
+    let copied = bytes.len().min(size - 1);
+    entry.extend_from_slice(&bytes[..copied]);
+    entry.resize(entry.len() + size - copied, 0);
+}
+
+/// Get the tag of a loaded program, which is the hash of its instructions
+fn program_tag(prog_fd: i32) -> [u8; 8] {
+    let mut info = bpf_prog_info::default();
+    let mut len = std::mem::size_of::<bpf_prog_info>() as u32;
+    // SAFETY: the info lives through the call, and its size is passed
+    let ret = unsafe { bpf_obj_get_info_by_fd(prog_fd, &mut info as *mut _ as *mut _, &mut len) };
+    if ret < 0 {
+        debug!("Failed to get info of program {}: {}", prog_fd, ret);
+        return [0; 8];
+    }
+    info.tag
+}
+
+/// Encode a program as `struct wasm_bpf_prog_info`:
+/// ```c
+/// struct wasm_bpf_prog_info {
+///     char name[64];
+///     char section[64];
+///     uint32_t prog_type; // enum bpf_prog_type
+///     int32_t fd;         // -1 if the program isn't loaded
+///     uint8_t tag[8];     // zero if the program isn't loaded
+/// };
+/// ```
+///
+/// # Safety
+/// `prog` must point to a valid program
+unsafe fn program_info_entry(prog: NonNull<bpf_program>) -> Vec<u8> {
+    let prog = prog.as_ptr();
+    let mut entry = Vec::with_capacity(NAME_SIZE * 2 + 16);
+    push_str_field(&mut entry, bpf_program__name(prog), NAME_SIZE);
+    push_str_field(&mut entry, bpf_program__section_name(prog), NAME_SIZE);
+    entry.extend_from_slice(&bpf_program__type(prog).to_le_bytes());
+    let fd = bpf_program__fd(prog).max(-1);
+    entry.extend_from_slice(&fd.to_le_bytes());
+    let tag = if fd < 0 { [0; 8] } else { program_tag(fd) };
+    entry.extend_from_slice(&tag);
+    entry
+}
+
+/// Encode a map as `struct wasm_bpf_map_info`:
+/// ```c
+/// struct wasm_bpf_map_info {
+///     char name[64];
+///     uint32_t map_type; // enum bpf_map_type
+///     uint32_t key_size;
+///     uint32_t value_size;
+///     uint32_t max_entries;
+///     uint32_t map_flags;
+///     int32_t fd;        // -1 if the map isn't created
+/// };
+/// ```
+///
+/// # Safety
+/// `map` must point to a valid map
+unsafe fn map_info_entry(map: NonNull<bpf_map>) -> Vec<u8> {
+    let map = map.as_ptr();
+    let mut entry = Vec::with_capacity(NAME_SIZE + 24);
+    push_str_field(&mut entry, bpf_map__name(map), NAME_SIZE);
+    for field in [
+        bpf_map__type(map),
+        bpf_map__key_size(map),
+        bpf_map__value_size(map),
+        bpf_map__max_entries(map),
+        bpf_map__map_flags(map),
+        bpf_map__fd(map).max(-1) as u32,
+    ] {
+        entry.extend_from_slice(&field.to_le_bytes());
+    }
+    entry
+}
+
+/// Get the info of all programs of a loaded object, in the order of the object file
+fn program_info_entries(obj: NonNull<bpf_object>) -> Vec<Vec<u8>> {
+    let mut entries = vec![];
+    let mut prog: *mut bpf_program = std::ptr::null_mut();
+    // SAFETY: the object is valid, and the programs belong to it
+    while let Some(v) = NonNull::new(unsafe { bpf_object__next_program(obj.as_ptr(), prog) }) {
+        entries.push(unsafe { program_info_entry(v) });
+        prog = v.as_ptr();
+    }
+    entries
+}
+
+/// Get the info of all maps of a loaded object, including the internal ones like `.bss`
+fn map_info_entries(obj: NonNull<bpf_object>) -> Vec<Vec<u8>> {
+    let mut entries = vec![];
+    let mut map: *mut bpf_map = std::ptr::null_mut();
+    // SAFETY: the object is valid, and the maps belong to it
+    while let Some(v) = NonNull::new(unsafe { bpf_object__next_map(obj.as_ptr(), map) }) {
+        entries.push(unsafe { map_info_entry(v) });
+        map = v.as_ptr();
+    }
+    entries
+}
+
+/// Write up to `count` entries into the guest memory, each one taking `entry_size` bytes.
+///
+/// `entry_size` versions the struct: fields beyond it aren't written,
+/// and bytes beyond the fields known by the runtime are zeroed.
+/// Returns the number of all entries
+fn write_entries(
+    caller: &mut CallerType,
+    entries: &[Vec<u8>],
+    buf: WasmPointer,
+    entry_size: u32,
+    count: u32,
+) -> i32 {
+    if count == 0 {
+        return entries.len() as i32;
+    }
+    if entry_size == 0 {
+        debug!("Invalid entry size: 0");
+        return -EINVAL;
+    }
+    let entry_size = entry_size as usize;
+    let size = entry_size * count as usize;
+    ensure_enough_memory!(*caller, buf, size, -EFAULT);
+    let mut data = Vec::with_capacity(size);
+    for entry in entries.iter().take(count as usize) {
+        let copied = entry.len().min(entry_size);
+        data.extend_from_slice(&entry[..copied]);
+        data.resize(data.len() + entry_size - copied, 0);
+    }
+    let memory = caller.get_memory().expect("Expected exported `memory`");
+    if let Err(err) = memory.write(&mut *caller, buf as usize, &data) {
+        debug!("Failed to write wasm memory: {}", err);
+        return -EFAULT;
+    }
+    entries.len() as i32
+}
+
+/// list the programs of a loaded bpf object, writing up to `count` of
+/// `struct wasm_bpf_prog_info` into `buf`, each one taking `entry_size` bytes.
+///
+/// Returns the number of all programs, which may be larger than `count`
+pub fn wasm_bpf_object_programs(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    buf: WasmPointer,
+    entry_size: u32,
+    count: u32,
+) -> i32 {
+    debug!("List programs of bpf object {}", program);
+    let object = ensure_program_by_caller!(caller, program);
+    let entries = program_info_entries(object.get_object().as_libbpf_bpf_object_ptr());
+    write_entries(&mut caller, &entries, buf, entry_size, count)
+}
+
+/// list the maps of a loaded bpf object, writing up to `count` of
+/// `struct wasm_bpf_map_info` into `buf`, each one taking `entry_size` bytes.
+///
+/// Returns the number of all maps, which may be larger than `count`
+pub fn wasm_bpf_object_maps(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    buf: WasmPointer,
+    entry_size: u32,
+    count: u32,
+) -> i32 {
+    debug!("List maps of bpf object {}", program);
+    let object = ensure_program_by_caller!(caller, program);
+    let entries = map_info_entries(object.get_object().as_libbpf_bpf_object_ptr());
+    write_entries(&mut caller, &entries, buf, entry_size, count)
+}
@@ -0,0 +1,165 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+
+use libbpf_rs::{
+    libbpf_sys::{perf_event_attr, PERF_FLAG_FD_CLOEXEC},
+    Link, ProgramType,
+};
+use log::debug;
+
+use crate::{ensure_c_str, ensure_program_mut_by_state, state::CallerType};
+
+use super::{
+    attach::{store_link, Attachment},
+    libbpf_errno, BpfObjectType, WasmString, EINVAL, ENOENT,
+};
+
+/// `flags` of `wasm_bpf_program_attach_perf_event`: `sample` is a frequency in Hz instead of a period
+const WASM_BPF_PERF_EVENT_FREQ: u32 = 1;
+
+/// Parse a cpu list like `0-3,5`, which is the format of `/sys/devices/system/cpu/online`
+pub(crate) fn parse_cpu_list(list: &str) -> Option<Vec<i32>> {
+    let mut cpus = vec![];
+    for range in list.trim().split(',') {
+        let (start, end): (i32, i32) = match range.split_once('-') {
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            None => {
+                let cpu = range.parse().ok()?;
+                (cpu, cpu)
+            }
+        };
+        if start > end {
+            return None;
+        }
+        cpus.extend(start..=end);
+    }
+    Some(cpus)
+}
+
+/// Get the online cpus
+fn online_cpus() -> Result<Vec<i32>, i32> {
+    let list = std::fs::read_to_string("/sys/devices/system/cpu/online").map_err(|e| {
+        debug!("Failed to read online cpus: {}", e);
+        -e.raw_os_error().unwrap_or(ENOENT)
+    })?;
+    parse_cpu_list(&list).ok_or_else(|| {
+        debug!("Invalid cpu list: {}", list);
+        -EINVAL
+    })
+}
+
+/// Open a disabled sampling perf event of all processes on `cpu`.
+/// It's enabled when a program is attached to it
+pub(crate) fn open_perf_event(
+    event_type: u32,
+    config: u64,
+    sample: u64,
+    freq: bool,
+    cpu: i32,
+) -> Result<OwnedFd, i32> {
+    let mut attr = perf_event_attr {
+        type_: event_type,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config,
+        ..Default::default()
+    };
+    if freq {
+        attr.__bindgen_anon_1.sample_freq = sample;
+    } else {
+        attr.__bindgen_anon_1.sample_period = sample;
+    }
+    attr.set_freq(freq as u64);
+    attr.set_disabled(1);
+    // SAFETY: the attributes live through the call
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const perf_event_attr,
+            -1,
+            cpu,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        let e = errno::errno();
+        debug!("Failed to open perf event on cpu {}: {}", cpu, e);
+        return Err(-e.0);
+    }
+    // SAFETY: the fd is just created, and is owned by nobody else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// attach a `SEC("perf_event")` program to perf events opened on every online cpu.
+/// `event_type` and `config` are those of `struct perf_event_attr`, like
+/// `PERF_TYPE_SOFTWARE` and `PERF_COUNT_SW_CPU_CLOCK`. `sample` is the sample period,
+/// or the frequency if `WASM_BPF_PERF_EVENT_FREQ` is set in `flags`.
+///
+/// The perf events are closed when the link is detached.
+/// Returns a positive link handle, or a negative value if failed
+pub fn wasm_bpf_program_attach_perf_event(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    event_type: u32,
+    config: u64,
+    sample: u64,
+    flags: u32,
+) -> i32 {
+    debug!("Attach perf event");
+    let name_str = ensure_c_str!(caller, name);
+    if flags & !WASM_BPF_PERF_EVENT_FREQ != 0 || sample == 0 {
+        debug!("Invalid perf event flags {:#x} or sample {}", flags, sample);
+        return -EINVAL;
+    }
+    let cpus = match online_cpus() {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    let state = caller.data_mut();
+    let object = ensure_program_mut_by_state!(state, program);
+    let links = {
+        let mut object_guard = object.get_object_mut();
+        let prog = match object_guard.prog_mut(&name_str) {
+            Some(v) => v,
+            None => {
+                debug!("No program named `{}` found", name_str);
+                return -ENOENT;
+            }
+        };
+        if !matches!(prog.prog_type(), ProgramType::PerfEvent) {
+            debug!("`{}` is not a perf_event program", name_str);
+            return -EINVAL;
+        }
+        let mut links: Vec<Link> = Vec::with_capacity(cpus.len());
+        for cpu in cpus {
+            let perf_event = match open_perf_event(
+                event_type,
+                config,
+                sample,
+                flags & WASM_BPF_PERF_EVENT_FREQ != 0,
+                cpu,
+            ) {
+                Ok(v) => v,
+                Err(e) => return e,
+            };
+            match prog.attach_perf_event(perf_event.as_raw_fd()) {
+                // The link owns the perf event from now on, and closes it when it's dropped
+                Ok(v) => {
+                    let _ = perf_event.into_raw_fd();
+                    links.push(v);
+                }
+                Err(e) => {
+                    debug!("Failed to attach perf event on cpu {}: {}", cpu, e);
+                    return libbpf_errno(&e);
+                }
+            }
+        }
+        links
+    };
+    store_link(&mut caller, program, Attachment::PerfEvents(links))
+}
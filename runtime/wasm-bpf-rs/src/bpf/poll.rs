@@ -3,27 +3,52 @@
 //! Copyright (c) 2023, eunomia-bpf
 //! All rights reserved.
 //!
-use std::{cell::RefCell, rc::Rc, time::Duration};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Result};
-use libbpf_rs::{MapType, PerfBufferBuilder, RingBufferBuilder};
+use libbpf_rs::{Map, MapType, PerfBufferBuilder, RingBufferBuilder};
 use log::{debug, error};
 use wasmtime::Val;
 
 use crate::{
-    bpf::{EINVAL, ENOENT},
-    ensure_enough_memory, ensure_program_mut_by_caller, ensure_program_mut_by_state,
+    ensure_enough_memory, ensure_program_by_caller,
     state::{
-        CallerType, PerfBufferContainerTryBuilder, PollBuffer, PollBufferImpl,
+        CallerType, PerfBufferContainerTryBuilder, PollBuffer, PollBufferImpl, PolledRecord,
         RingBufferContainerTryBuilder,
     },
     utils::{CallerUtils, FunctionQuickCall},
 };
 
-use super::{BpfObjectType, WasmPointer};
+use super::{libbpf_errno, BpfObjectType, WasmPointer, EBADF, EFAULT, EINVAL, ENOENT};
 
 type SampleCallbackParams = (u32, u32, u32);
+type SampleWithFdCallbackParams = (u32, i32, u32, u32);
+type SampleWithCpuCallbackParams = (u32, i32, u32, u32);
 type SampleCallbackReturn = i32;
+type BatchCallbackParams = (u32, u32, u32, u32);
+
+/// The most maps that a single poller polls
+const MAX_POLL_FDS: u32 = 256;
+
+/// The size of the length prefix of each record in a batch
+const BATCH_RECORD_HEADER_SIZE: usize = 4;
+/// Records in a batch are aligned to this, so the length prefixes are aligned
+const BATCH_RECORD_ALIGN: usize = 4;
+
+/// The guest function that records will be delivered to
+#[derive(Clone, Copy)]
+enum SampleCallback {
+    /// `int (*)(void *ctx, void *data, size_t size)`, or the callback export of the go sdk
+    Single(WasmPointer),
+    /// `int (*)(void *ctx, int fd, void *data, size_t size)`
+    WithMapFd(WasmPointer),
+    /// `int (*)(void *ctx, int cpu, void *data, size_t size)`
+    WithCpu(WasmPointer),
+}
 
 /// polling the bpf buffer
 ///
@@ -43,133 +68,421 @@ pub fn wasm_bpf_buffer_poll(
         "wasm_bpf_buffer_poll: program: {:?}, fd: {}, sample_func: {:?}, ctx: {:?}, data: {:?}, max_size: {}, timeout_ms: {}",
         program, fd, sample_func, ctx, data, max_size, timeout_ms);
     // Ensure that there is enough memory in the wasm side
-    ensure_enough_memory!(caller, data, max_size, EINVAL);
-    let object_rc = match caller.data().object_map.get(&program) {
-        Some(v) => v.get_object_rc(),
+    ensure_enough_memory!(caller, data, max_size, -EFAULT);
+    poll_and_deliver(
+        &mut caller,
+        program,
+        vec![fd],
+        SampleCallback::Single(sample_func),
+        ctx,
+        data,
+        max_size,
+        timeout_ms,
+    )
+}
+
+/// polling the bpf buffer, passing the cpu number of each record to the sample function.
+/// The cpu number is -1 for ring buffers
+#[allow(clippy::too_many_arguments)]
+pub fn wasm_bpf_buffer_poll_with_cpu(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    sample_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+) -> i32 {
+    debug!(
+        "wasm_bpf_buffer_poll_with_cpu: program: {:?}, fd: {}, sample_func: {:?}, ctx: {:?}, data: {:?}, max_size: {}, timeout_ms: {}",
+        program, fd, sample_func, ctx, data, max_size, timeout_ms);
+    ensure_enough_memory!(caller, data, max_size, -EFAULT);
+    poll_and_deliver(
+        &mut caller,
+        program,
+        vec![fd],
+        SampleCallback::WithCpu(sample_func),
+        ctx,
+        data,
+        max_size,
+        timeout_ms,
+    )
+}
+
+/// get the number of samples lost by the buffer of the map `fd` since it was first polled
+///
+/// Returns the count, or a negative errno if the map isn't being polled
+pub fn wasm_bpf_buffer_lost_count(mut caller: CallerType, program: BpfObjectType, fd: i32) -> i64 {
+    debug!(
+        "wasm_bpf_buffer_lost_count: program: {}, fd: {}",
+        program, fd
+    );
+    let object = ensure_program_by_caller!(caller, program);
+    match object
+        .poll_buffers
+        .iter()
+        .find(|(map_fds, _)| map_fds.contains(&fd))
+    {
+        Some((_, poller)) => poller.lost_samples.get() as i64,
         None => {
-            error!("Invalid program handle: {}", program);
-            return -1;
+            debug!("The map with fd {} is not being polled", fd);
+            -ENOENT as i64
         }
+    }
+}
+
+/// polling several ring buffers of a bpf object with a single poller
+///
+/// `fds` points to an array of `fds_count` map fds, at most `MAX_POLL_FDS`. The sample function receives
+/// the fd of the map that each record comes from.
+#[allow(clippy::too_many_arguments)]
+pub fn wasm_bpf_buffer_poll_multi(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fds: WasmPointer,
+    fds_count: u32,
+    sample_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+) -> i32 {
+    debug!(
+        "wasm_bpf_buffer_poll_multi: program: {:?}, fds: {:?}, fds_count: {}, sample_func: {:?}, ctx: {:?}, data: {:?}, max_size: {}, timeout_ms: {}",
+        program, fds, fds_count, sample_func, ctx, data, max_size, timeout_ms);
+    if fds_count == 0 || fds_count > MAX_POLL_FDS {
+        debug!("Invalid number of map fds to poll: {}", fds_count);
+        return -EINVAL;
+    }
+    let Some(fds_size) = (fds_count as usize).checked_mul(4) else {
+        return -EINVAL;
     };
-    let object = object_rc.borrow();
-    let map = if let Some(map) = object.maps_iter().find(|v| v.fd() == fd) {
-        map
-    } else {
-        error!("No map with fd {} found!", fd);
-        return -ENOENT;
+    ensure_enough_memory!(caller, fds, fds_size, -EFAULT);
+    ensure_enough_memory!(caller, data, max_size, -EFAULT);
+    let mut fds_buf = vec![0u8; fds_size];
+    let memory = caller.get_memory().expect("Expected exported `memory`");
+    if let Err(e) = memory.read(&mut caller, fds as usize, &mut fds_buf) {
+        error!("Failed to read wasm memory: {}", e);
+        return -EFAULT;
+    }
+    let mut map_fds: Vec<i32> = fds_buf
+        .chunks_exact(4)
+        .map(|v| i32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+        .collect();
+    // The same set of maps shares the same poller, regardless of the order
+    map_fds.sort_unstable();
+    map_fds.dedup();
+    poll_and_deliver(
+        &mut caller,
+        program,
+        map_fds,
+        SampleCallback::WithMapFd(sample_func),
+        ctx,
+        data,
+        max_size,
+        timeout_ms,
+    )
+}
+
+/// polling the bpf buffer, delivering many records with a single call to the guest
+///
+/// Records are packed into `data` one after another, each one prefixed with its
+/// size as a little-endian u32 and padded to 4 bytes. The batch function is
+/// `int (*)(void *ctx, void *data, size_t size, uint32_t count)`, where `size`
+/// is the bytes used in `data` and `count` is the number of records.
+/// Records larger than the buffer are truncated.
+#[allow(clippy::too_many_arguments)]
+pub fn wasm_bpf_buffer_poll_batch(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    batch_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+) -> i32 {
+    debug!(
+        "wasm_bpf_buffer_poll_batch: program: {:?}, fd: {}, batch_func: {:?}, ctx: {:?}, data: {:?}, max_size: {}, timeout_ms: {}",
+        program, fd, batch_func, ctx, data, max_size, timeout_ms);
+    if max_size as usize <= BATCH_RECORD_HEADER_SIZE {
+        debug!("The buffer can't hold any record");
+        return -EINVAL;
+    }
+    ensure_enough_memory!(caller, data, max_size, -EFAULT);
+    let result_container = match poll_records(&mut caller, program, vec![fd], timeout_ms) {
+        Ok(v) => v,
+        Err(e) => return e,
     };
-    let object = ensure_program_mut_by_caller!(caller, program);
-    if object.poll_buffer.is_none() {
-        // Create the poller if it's not created
-        let result_recv = Rc::new(RefCell::new(Some(Vec::<u8>::new())));
-        let poll_impl = match map.map_type() {
-            MapType::PerfEventArray => {
-                let local_cb = {
-                    let result_recv = result_recv.clone();
-                    Box::new(move |_cpu: i32, data: &[u8]| {
-                        result_recv.borrow_mut().replace(data.to_vec());
-                    })
-                };
-                let perf_buffer = PerfBufferContainerTryBuilder {
-                    callback_func: local_cb,
-                    perfbuf_builder: |v| PerfBufferBuilder::new(map).sample_cb(v).build(),
+    let max_size = max_size as usize;
+    let mut batch = Vec::with_capacity(max_size);
+    // Deliver the records batch by batch, until the callback asks to stop
+    loop {
+        batch.clear();
+        let mut count = 0u32;
+        {
+            let mut records = result_container.borrow_mut();
+            while let Some(record) = records.front() {
+                let record_size = record.data.len().min(max_size - BATCH_RECORD_HEADER_SIZE);
+                if batch.len() + BATCH_RECORD_HEADER_SIZE + record_size > max_size {
+                    break;
                 }
-                .try_build();
-                let perf_buffer = match perf_buffer {
-                    Err(e) => {
-                        error!("Failed to build perfbuffer: {}", e);
-                        return 1;
-                    }
-                    Ok(v) => v,
-                };
-                PollBuffer {
-                    inner: PollBufferImpl::PerfEvent(perf_buffer),
-                    result_container: result_recv,
+                batch.extend_from_slice(&(record_size as u32).to_le_bytes());
+                batch.extend_from_slice(&record.data[..record_size]);
+                let padded_len = batch.len().div_ceil(BATCH_RECORD_ALIGN) * BATCH_RECORD_ALIGN;
+                batch.resize(padded_len.min(max_size), 0);
+                records.pop_front();
+                count += 1;
+            }
+        }
+        if count == 0 {
+            break;
+        }
+        let memory = caller.get_memory().expect("Expected exported `memory`");
+        if let Err(e) = memory.write(&mut caller, data as usize, &batch) {
+            error!("Failed to write wasm memory: {}", e);
+            return -EFAULT;
+        }
+        let ret = match caller.perform_indirect_call::<BatchCallbackParams, SampleCallbackReturn>(
+            batch_func,
+            (ctx, data, batch.len() as u32, count),
+        ) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to perform indirect call when polling: {}", e);
+                return -EINVAL;
+            }
+        };
+        if ret != 0 {
+            return ret;
+        }
+    }
+    0
+}
+
+/// Create the poller of the maps
+fn build_poll_buffer(maps: &[&Map]) -> Result<PollBuffer, i32> {
+    let result_recv = Rc::new(RefCell::new(VecDeque::<PolledRecord>::new()));
+    let lost_samples = Rc::new(Cell::new(0u64));
+    let poll_impl = match maps {
+        [map] if map.map_type() == MapType::PerfEventArray => {
+            let map_fd = map.fd();
+            let local_cb = {
+                let result_recv = result_recv.clone();
+                Box::new(move |cpu: i32, data: &[u8]| {
+                    result_recv.borrow_mut().push_back(PolledRecord {
+                        map_fd,
+                        cpu,
+                        data: data.to_vec(),
+                    });
+                })
+            };
+            let lost_cb = {
+                let lost_samples = lost_samples.clone();
+                move |cpu: i32, count: u64| {
+                    debug!("Lost {} samples on cpu {} of map {}", count, cpu, map_fd);
+                    lost_samples.set(lost_samples.get() + count);
                 }
+            };
+            let perf_buffer = PerfBufferContainerTryBuilder {
+                callback_func: local_cb,
+                perfbuf_builder: |v| {
+                    PerfBufferBuilder::new(map)
+                        .sample_cb(v)
+                        .lost_cb(lost_cb)
+                        .build()
+                },
             }
-            MapType::RingBuf => {
-                let local_cb = {
-                    let result_recv = result_recv.clone();
-                    Box::new(move |data: &[u8]| -> i32 {
-                        result_recv.borrow_mut().replace(data.to_vec());
-                        0
-                    })
-                };
-                let ring_buffer: Result<_> = RingBufferContainerTryBuilder {
-                    callback_func: local_cb,
-                    ringbuf_builder: |v| {
-                        let mut ringbuf = RingBufferBuilder::new();
-                        ringbuf
-                            .add(map, v)
-                            .map_err(|e| anyhow!("Failed to add callback for ringbuf: {}", e))?;
-                        ringbuf
-                            .build()
-                            .map_err(|e| anyhow!("Failed to build ringbuf: {}", e))
-                    },
+            .try_build();
+            match perf_buffer {
+                Err(e) => {
+                    error!("Failed to build perfbuffer: {}", e);
+                    return Err(libbpf_errno(&e));
                 }
-                .try_build();
-                let ring_buffer = match ring_buffer {
-                    Err(e) => {
-                        error!("Failed to build ringbuffer: {}", e);
-                        return 1;
+                Ok(v) => PollBufferImpl::PerfEvent(v),
+            }
+        }
+        maps if maps.iter().all(|v| v.map_type() == MapType::RingBuf) => {
+            let local_cb = {
+                let result_recv = result_recv.clone();
+                Box::new(move |map_fd: i32, data: &[u8]| -> i32 {
+                    result_recv.borrow_mut().push_back(PolledRecord {
+                        map_fd,
+                        cpu: -1,
+                        data: data.to_vec(),
+                    });
+                    0
+                })
+            };
+            let ring_buffer: Result<_, libbpf_rs::Error> = RingBufferContainerTryBuilder {
+                callback_func: local_cb,
+                ringbuf_builder: |v| {
+                    let mut ringbuf = RingBufferBuilder::new();
+                    // One ring buffer manager serves all of the maps
+                    for map in maps {
+                        let map_fd = map.fd();
+                        ringbuf.add(map, move |data: &[u8]| v(map_fd, data))?;
                     }
-                    Ok(v) => v,
-                };
-                PollBuffer {
-                    inner: PollBufferImpl::RingBuf(ring_buffer),
-                    result_container: result_recv,
+                    ringbuf.build()
+                },
+            }
+            .try_build();
+            match ring_buffer {
+                Err(e) => {
+                    error!("Failed to build ringbuffer: {}", e);
+                    return Err(libbpf_errno(&e));
                 }
+                Ok(v) => PollBufferImpl::RingBuf(v),
+            }
+        }
+        maps => {
+            let types: Vec<_> = maps.iter().map(|v| v.map_type()).collect();
+            error!("Unsupported map types for polling: {:?}", types);
+            return Err(-EINVAL);
+        }
+    };
+    Ok(PollBuffer {
+        inner: poll_impl,
+        result_container: result_recv,
+        lost_samples,
+    })
+}
+
+/// Poll the maps with the poller created for them.
+/// A map can only be polled by one poller, since the records are consumed by the poller.
+/// Returns the queue holding the records received and not delivered yet
+fn poll_records(
+    caller: &mut CallerType,
+    program: BpfObjectType,
+    map_fds: Vec<i32>,
+    timeout_ms: i32,
+) -> Result<Rc<RefCell<VecDeque<PolledRecord>>>, i32> {
+    let object_rc = match caller.data().object_map.get(&program) {
+        Some(v) => v.get_object_rc(),
+        None => {
+            error!("Invalid program handle: {}", program);
+            return Err(-EBADF);
+        }
+    };
+    let object_guard = object_rc.borrow();
+    let object = match caller.data_mut().object_map.get_mut(&program) {
+        Some(v) => v,
+        None => {
+            error!("Invalid program handle: {}", program);
+            return Err(-EBADF);
+        }
+    };
+    if !object.poll_buffers.contains_key(&map_fds) {
+        // Create the poller if it's not created
+        if let Some(fd) = map_fds
+            .iter()
+            .find(|fd| object.poll_buffers.keys().any(|v| v.contains(fd)))
+        {
+            error!("The map with fd {} is already polled with other maps", fd);
+            return Err(-EINVAL);
+        }
+        let mut maps = Vec::with_capacity(map_fds.len());
+        for fd in map_fds.iter() {
+            if let Some(map) = object_guard.maps_iter().find(|v| v.fd() == *fd) {
+                maps.push(map);
+            } else {
+                error!("No map with fd {} found!", fd);
+                return Err(-ENOENT);
             }
-            s => {
-                error!("Unsupported map type for polling: {}", s);
-                return -1;
+        }
+        match build_poll_buffer(&maps) {
+            Ok(v) => {
+                object.poll_buffers.insert(map_fds.clone(), v);
             }
-        };
-        object.poll_buffer = Some(poll_impl);
+            Err(e) => return Err(e),
+        }
     }
 
     let result_container = {
-        let poller = object.poll_buffer.as_ref().unwrap();
-        // Clean result
-        poller.result_container.borrow_mut().take();
+        let poller = &object.poll_buffers[&map_fds];
+        // Records left by the last call are delivered first, so don't wait for new ones
+        let timeout = if poller.result_container.borrow().is_empty() {
+            Duration::from_millis(timeout_ms as u64)
+        } else {
+            Duration::ZERO
+        };
         match &poller.inner {
             PollBufferImpl::RingBuf(rb) => {
-                if let Err(e) = rb
-                    .borrow_ringbuf()
-                    .poll(Duration::from_millis(timeout_ms as u64))
-                {
+                if let Err(e) = rb.borrow_ringbuf().poll(timeout) {
                     error!("Failed to poll ringbuf: {}", e);
-                    return -1;
+                    return Err(libbpf_errno(&e));
                 }
             }
             PollBufferImpl::PerfEvent(perf) => {
-                if let Err(e) = perf
-                    .borrow_perfbuf()
-                    .poll(Duration::from_millis(timeout_ms as u64))
-                {
+                if let Err(e) = perf.borrow_perfbuf().poll(timeout) {
                     error!("Failed to poll perf event: {}", e);
-                    return -1;
+                    return Err(libbpf_errno(&e));
                 }
             }
         }
         poller.result_container.clone()
     };
-    // Here we could try to extract the result
-    if let Some(v) = result_container.borrow_mut().take() {
-        let memory = match caller.get_memory() {
-            Err(e) => {
-                error!("Failed to get exported memory: {}", e);
-                return -1;
-            }
-            Ok(v) => v,
+    Ok(result_container)
+}
+
+/// Poll the maps with the poller created for them, and deliver the records to the guest
+#[allow(clippy::too_many_arguments)]
+fn poll_and_deliver(
+    caller: &mut CallerType,
+    program: BpfObjectType,
+    map_fds: Vec<i32>,
+    sample_func: SampleCallback,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+) -> i32 {
+    // The object isn't borrowed after polling, so the guest may operate it in the callback
+    let result_container = match poll_records(caller, program, map_fds, timeout_ms) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    // Deliver the records one by one, until the callback asks to stop.
+    // The records not delivered will be kept for the next call
+    loop {
+        let record = match result_container.borrow_mut().pop_front() {
+            Some(v) => v,
+            None => break,
         };
-        let bytes_to_write = v.len().min(max_size as usize);
-        if let Err(e) = memory.write(&mut caller, data as usize, &v[..bytes_to_write]) {
-            error!("Failed to write wasm memory: {}", e);
-            return -1;
+        let ret = deliver_record(caller, sample_func, ctx, data, max_size, &record);
+        if ret != 0 {
+            return ret;
         }
-        // Call the callback
-        if caller.data().wrapper_called {
+    }
+    0
+}
+
+/// Write a record into the wasm memory, and call the sample callback with it.
+/// Returns what the callback returns, or `-EINVAL` if it can't be called
+fn deliver_record(
+    caller: &mut CallerType,
+    sample_func: SampleCallback,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    record: &PolledRecord,
+) -> i32 {
+    let memory = match caller.get_memory() {
+        Err(e) => {
+            error!("Failed to get exported memory: {}", e);
+            return -EFAULT;
+        }
+        Ok(v) => v,
+    };
+    let bytes_to_write = record.data.len().min(max_size as usize);
+    if let Err(e) = memory.write(&mut *caller, data as usize, &record.data[..bytes_to_write]) {
+        error!("Failed to write wasm memory: {}", e);
+        return -EFAULT;
+    }
+    // Call the callback
+    let result = match sample_func {
+        SampleCallback::Single(_) if caller.data().wrapper_called => {
             let mut result = [Val::I32(0)];
             let callback = caller.data().callback_func_name.clone();
             if let Err(err) = caller
@@ -178,7 +491,7 @@ pub fn wasm_bpf_buffer_poll(
                 .into_func()
                 .unwrap()
                 .call(
-                    &mut caller,
+                    &mut *caller,
                     &[
                         // Seems that tinygo cannot produce unsigned integer types, so just let wasmtiime to perform the conversion
                         Val::I32(ctx as i32),
@@ -189,27 +502,36 @@ pub fn wasm_bpf_buffer_poll(
                 )
             {
                 error!("Failed to call the callback through direct export: {}", err);
-                return -1;
+                return -EINVAL;
             }
-        } else {
-            match caller.perform_indirect_call::<SampleCallbackParams, SampleCallbackReturn>(
-                sample_func,
+            Ok(result[0].i32().unwrap_or(0))
+        }
+        SampleCallback::Single(func) => caller
+            .perform_indirect_call::<SampleCallbackParams, SampleCallbackReturn>(
+                func,
                 (ctx, data, bytes_to_write as u32),
-            ) {
-                Ok(v) => {
-                    return v;
-                }
-                Err(e) => {
-                    error!(
-                        "Failed to perform indirect call when polling: {} ; {}\n{}",
-                        e.to_string(),
-                        e.root_cause(),
-                        e.backtrace()
-                    );
-                    return 0;
-                }
-            }
+            ),
+        SampleCallback::WithMapFd(func) => caller
+            .perform_indirect_call::<SampleWithFdCallbackParams, SampleCallbackReturn>(
+                func,
+                (ctx, record.map_fd, data, bytes_to_write as u32),
+            ),
+        SampleCallback::WithCpu(func) => caller
+            .perform_indirect_call::<SampleWithCpuCallbackParams, SampleCallbackReturn>(
+                func,
+                (ctx, record.cpu, data, bytes_to_write as u32),
+            ),
+    };
+    match result {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "Failed to perform indirect call when polling: {} ; {}\n{}",
+                e.to_string(),
+                e.root_cause(),
+                e.backtrace()
+            );
+            -EINVAL
         }
     }
-    0
 }
@@ -0,0 +1,202 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use libbpf_rs::ProgramType;
+use log::debug;
+
+use crate::{
+    ensure_c_str, ensure_enough_memory, ensure_program_mut_by_state, state::CallerType,
+    utils::CallerUtils,
+};
+
+use super::{
+    attach::{interface_index, store_link, Attachment},
+    BpfObjectType, WasmPointer, WasmString, EBADF, EFAULT, EINVAL, ENOENT,
+};
+
+/// A raw `AF_PACKET` socket, with a socket filter program attached
+pub struct PacketSocket {
+    socket: OwnedFd,
+}
+
+impl PacketSocket {
+    /// Open a raw packet socket bound to the interface, or all interfaces if `ifindex` is 0,
+    /// and attach the socket filter program to it
+    pub(crate) fn open(ifindex: i32, prog_fd: i32) -> Result<Self, i32> {
+        // A packet socket of protocol 0 receives nothing until it's bound with a protocol
+        // SAFETY: no pointers are passed
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            let e = errno::errno();
+            debug!("Failed to create packet socket: {}", e);
+            return Err(-e.0);
+        }
+        // SAFETY: the fd is just created, and is owned by nobody else
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        // Attach the filter before binding with ETH_P_ALL, so that no unfiltered packets are queued
+        // SAFETY: the option is an int living through the call
+        let err = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_BPF,
+                &prog_fd as *const i32 as *const libc::c_void,
+                std::mem::size_of::<i32>() as libc::socklen_t,
+            )
+        };
+        if err != 0 {
+            let e = errno::errno();
+            debug!("Failed to attach socket filter: {}", e);
+            return Err(-e.0);
+        }
+        // SAFETY: all-zero is a valid sockaddr_ll
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        addr.sll_ifindex = ifindex;
+        // SAFETY: the address lives through the call
+        let err = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if err != 0 {
+            let e = errno::errno();
+            debug!("Failed to bind packet socket to {}: {}", ifindex, e);
+            return Err(-e.0);
+        }
+        Ok(Self { socket })
+    }
+
+    /// Wait for a packet at most `timeout_ms` and receive it.
+    /// Returns the size of the packet, which may be larger than the buffer, or 0 if timed out
+    pub(crate) fn recv(&self, buf: &mut [u8], timeout_ms: i32) -> Result<usize, i32> {
+        let mut pollfd = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: the pollfd lives through the call
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if ready < 0 {
+            let e = errno::errno();
+            debug!("Failed to poll packet socket: {}", e);
+            return Err(-e.0);
+        }
+        if ready == 0 {
+            return Ok(0);
+        }
+        // SAFETY: the buffer is valid for its length
+        let size = unsafe {
+            libc::recv(
+                self.socket.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_DONTWAIT | libc::MSG_TRUNC,
+            )
+        };
+        if size < 0 {
+            let e = errno::errno();
+            if e.0 == libc::EAGAIN {
+                return Ok(0);
+            }
+            debug!("Failed to receive from packet socket: {}", e);
+            return Err(-e.0);
+        }
+        Ok(size as usize)
+    }
+}
+
+/// open a raw packet socket on `interface`, or all interfaces if it's null,
+/// and attach the socket filter program `name` to it.
+///
+/// Returns a positive handle for `wasm_bpf_socket_filter_read`, which can be
+/// closed by `wasm_bpf_link_detach`, or a negative value if failed
+pub fn wasm_bpf_socket_filter_open(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    interface: WasmString, // Allow null pointers
+) -> i32 {
+    debug!("Open socket filter");
+    let name_str = ensure_c_str!(caller, name);
+    let ifindex = if interface == 0 {
+        0
+    } else {
+        match interface_index(&ensure_c_str!(caller, interface)) {
+            Ok(v) => v,
+            Err(e) => return e,
+        }
+    };
+    let state = caller.data_mut();
+    let object = ensure_program_mut_by_state!(state, program);
+    let prog_fd = {
+        let object_guard = object.get_object();
+        let prog = match object_guard.prog(&name_str) {
+            Some(v) => v,
+            None => {
+                debug!("No program named `{}` found", name_str);
+                return -ENOENT;
+            }
+        };
+        if !matches!(prog.prog_type(), ProgramType::SocketFilter) {
+            debug!("`{}` is not a socket filter", name_str);
+            return -EINVAL;
+        }
+        prog.fd()
+    };
+    match PacketSocket::open(ifindex, prog_fd) {
+        Ok(v) => store_link(&mut caller, program, Attachment::Socket(v)),
+        Err(e) => e,
+    }
+}
+
+/// wait for a packet passed by the socket filter at most `timeout_ms`, and copy it into `buf`.
+///
+/// Returns the size of the packet, which may be larger than `size` if it's truncated,
+/// 0 if timed out, or a negative value if failed
+pub fn wasm_bpf_socket_filter_read(
+    mut caller: CallerType,
+    handle: i32,
+    buf: WasmPointer,
+    size: u32,
+    timeout_ms: i32,
+) -> i32 {
+    ensure_enough_memory!(caller, buf, size, -EFAULT);
+    let mut packet = vec![0u8; size as usize];
+    let result = caller
+        .data()
+        .object_map
+        .values()
+        .find_map(|v| match v.links.get(&handle) {
+            Some(Attachment::Socket(socket)) => Some(socket.recv(&mut packet, timeout_ms)),
+            _ => None,
+        });
+    let packet_size = match result {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => return e,
+        None => {
+            debug!("Invalid socket filter handle: {}", handle);
+            return -EBADF;
+        }
+    };
+    let copied = packet_size.min(packet.len());
+    let memory = caller.get_memory().expect("Expected exported `memory`");
+    if let Err(e) = memory.write(&mut caller, buf as usize, &packet[..copied]) {
+        debug!("Failed to write wasm memory: {}", e);
+        return -EFAULT;
+    }
+    packet_size as i32
+}
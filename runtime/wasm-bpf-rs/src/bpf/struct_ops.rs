@@ -0,0 +1,85 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use libbpf_rs::{Map, MapType};
+use log::debug;
+
+use crate::{
+    ensure_c_str, ensure_program_mut_by_state,
+    state::{AppState, CallerType},
+};
+
+use super::{
+    attach::{store_link, Attachment},
+    libbpf_errno, BpfObjectType, WasmString, EBADF, EINVAL, ENOENT,
+};
+
+/// Register a struct_ops map, like a TCP congestion control algorithm, to the kernel
+fn attach_struct_ops(map: &Map) -> Result<Attachment, i32> {
+    match map.attach_struct_ops() {
+        Ok(v) => {
+            debug!("struct_ops map `{}` attached with link {:?}", map.name(), v);
+            Ok(Attachment::Link(v))
+        }
+        Err(err) => {
+            debug!("Failed to attach struct_ops map `{}`: {}", map.name(), err);
+            Err(libbpf_errno(&err))
+        }
+    }
+}
+
+/// Attach all the struct_ops maps of a loaded object, keeping the links with the object.
+/// It's used when `Config::auto_attach_struct_ops` is set
+pub(crate) fn attach_all_struct_ops(
+    state: &mut AppState,
+    program: BpfObjectType,
+) -> Result<(), i32> {
+    let object = state.object_map.get_mut(&program).ok_or(-EBADF)?;
+    let attachments = object
+        .get_object()
+        .maps_iter()
+        .filter(|v| v.map_type() == MapType::StructOps)
+        .map(attach_struct_ops)
+        .collect::<Result<Vec<_>, i32>>()?;
+    for attachment in attachments {
+        object.links.insert(state.next_link_id, attachment);
+        state.next_link_id += 1;
+    }
+    Ok(())
+}
+
+/// attach a struct_ops map of the object, like a TCP congestion control algorithm
+/// defined in `SEC(".struct_ops")`. The map is unregistered when the link is detached.
+///
+/// Returns a positive link handle, or a negative value if failed
+pub fn wasm_bpf_struct_ops_attach(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    map_name: WasmString,
+) -> i32 {
+    debug!("Attach struct_ops");
+    let map_name_str = ensure_c_str!(caller, map_name);
+    let state = caller.data_mut();
+    let object = ensure_program_mut_by_state!(state, program);
+    let result = {
+        let object_guard = object.get_object();
+        let map = match object_guard.map(&map_name_str) {
+            Some(v) => v,
+            None => {
+                debug!("No map named `{}` found", map_name_str);
+                return -ENOENT;
+            }
+        };
+        if map.map_type() != MapType::StructOps {
+            debug!("`{}` is not a struct_ops map", map_name_str);
+            return -EINVAL;
+        }
+        attach_struct_ops(map)
+    };
+    match result {
+        Ok(v) => store_link(&mut caller, program, v),
+        Err(e) => e,
+    }
+}
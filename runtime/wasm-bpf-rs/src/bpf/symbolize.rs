@@ -0,0 +1,288 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::collections::{hash_map::Entry, HashMap};
+
+use log::debug;
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+
+use crate::{state::CallerType, utils::CallerUtils};
+
+use super::{WasmPointer, EFAULT, EINVAL, ENOENT};
+
+/// A function symbol
+#[derive(Debug)]
+struct Symbol {
+    address: u64,
+    /// 0 if unknown, like symbols in kallsyms
+    size: u64,
+    name: String,
+}
+
+/// Function symbols sorted by address
+#[derive(Debug, Default)]
+pub(crate) struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|v| v.address);
+        Self { symbols }
+    }
+
+    /// Parse `/proc/kallsyms`. Only text symbols are kept
+    pub(crate) fn from_kallsyms(kallsyms: &str) -> Self {
+        let symbols = kallsyms
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                let address = u64::from_str_radix(parts.next()?, 16).ok()?;
+                let kind = parts.next()?;
+                let name = parts.next()?;
+                matches!(kind, "t" | "T" | "w" | "W").then(|| Symbol {
+                    address,
+                    size: 0,
+                    name: name.to_string(),
+                })
+            })
+            .collect();
+        Self::new(symbols)
+    }
+
+    /// Find the symbol containing `address`, and return its name and the offset in it.
+    /// A symbol without a size is taken as lasting until the next one
+    pub(crate) fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|v| v.address <= address);
+        let symbol = &self.symbols[index.checked_sub(1)?];
+        let offset = address - symbol.address;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((&symbol.name, offset))
+    }
+}
+
+/// The function symbols of an ELF file, and its segments for translating file offsets
+#[derive(Debug)]
+struct ElfSymbols {
+    symbols: SymbolTable,
+    /// (file offset, file size, virtual address) of each segment
+    segments: Vec<(u64, u64, u64)>,
+}
+
+impl ElfSymbols {
+    /// Read the symbols from `.symtab` and `.dynsym` of an ELF file
+    fn load(path: &str) -> Result<Self, i32> {
+        let data = std::fs::read(path).map_err(|e| {
+            debug!("Failed to read `{}`: {}", path, e);
+            -e.raw_os_error().unwrap_or(ENOENT)
+        })?;
+        let file = object::File::parse(&*data).map_err(|e| {
+            debug!("Failed to parse ELF `{}`: {}", path, e);
+            -EINVAL
+        })?;
+        let symbols = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|v| v.kind() == SymbolKind::Text && !v.is_undefined())
+            .filter_map(|v| {
+                Some(Symbol {
+                    address: v.address(),
+                    size: v.size(),
+                    name: v.name().ok()?.to_string(),
+                })
+            })
+            .collect();
+        let segments = file
+            .segments()
+            .map(|v| {
+                let (offset, size) = v.file_range();
+                (offset, size, v.address())
+            })
+            .collect();
+        Ok(Self {
+            symbols: SymbolTable::new(symbols),
+            segments,
+        })
+    }
+
+    /// Look up the symbol at an offset in the file
+    fn lookup_file_offset(&self, file_offset: u64) -> Option<(&str, u64)> {
+        let address = self
+            .segments
+            .iter()
+            .find(|(offset, size, _)| file_offset >= *offset && file_offset < offset + size)
+            .map(|(offset, _, address)| file_offset - offset + address)?;
+        self.symbols.lookup(address)
+    }
+}
+
+/// A file mapped into a process
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Mapping {
+    pub start: u64,
+    pub end: u64,
+    pub offset: u64,
+    pub path: String,
+}
+
+/// Parse `/proc/<pid>/maps`. Only mapped files are kept
+pub(crate) fn parse_proc_maps(maps: &str) -> Vec<Mapping> {
+    maps.lines()
+        .filter_map(|line| {
+            // `start-end perms offset dev inode path`
+            let mut parts = line.split_whitespace();
+            let (start, end) = parts.next()?.split_once('-')?;
+            let offset = parts.nth(1)?;
+            let path = parts.nth(2)?;
+            if !path.starts_with('/') {
+                return None;
+            }
+            Some(Mapping {
+                start: u64::from_str_radix(start, 16).ok()?,
+                end: u64::from_str_radix(end, 16).ok()?,
+                offset: u64::from_str_radix(offset, 16).ok()?,
+                path: path.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The mappings of a process, and the symbols of the files mapped
+#[derive(Debug)]
+struct ProcessSymbols {
+    mappings: Vec<Mapping>,
+    /// Files which failed to load are kept as errors, so that they aren't read again
+    files: HashMap<String, Result<ElfSymbols, i32>>,
+}
+
+/// Symbols cached for resolving addresses in stack traces
+#[derive(Debug, Default)]
+pub(crate) struct Symbolizer {
+    kernel: Option<SymbolTable>,
+    processes: HashMap<i32, ProcessSymbols>,
+}
+
+impl Symbolizer {
+    /// Resolve a kernel address through `/proc/kallsyms`, which is read at the first lookup
+    pub(crate) fn resolve_kernel(&mut self, address: u64) -> Result<(&str, u64), i32> {
+        if self.kernel.is_none() {
+            let kallsyms = std::fs::read_to_string("/proc/kallsyms").map_err(|e| {
+                debug!("Failed to read kallsyms: {}", e);
+                -e.raw_os_error().unwrap_or(ENOENT)
+            })?;
+            self.kernel = Some(SymbolTable::from_kallsyms(&kallsyms));
+        }
+        self.kernel
+            .as_ref()
+            .and_then(|v| v.lookup(address))
+            .ok_or(-ENOENT)
+    }
+
+    /// Resolve a user address of a process through the symbols of the mapped ELF files.
+    /// The mappings are read at the first lookup of the process
+    pub(crate) fn resolve_user(&mut self, pid: i32, address: u64) -> Result<(&str, u64), i32> {
+        let process = match self.processes.entry(pid) {
+            Entry::Occupied(v) => v.into_mut(),
+            Entry::Vacant(v) => {
+                let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid)).map_err(|e| {
+                    debug!("Failed to read maps of {}: {}", pid, e);
+                    -e.raw_os_error().unwrap_or(ENOENT)
+                })?;
+                v.insert(ProcessSymbols {
+                    mappings: parse_proc_maps(&maps),
+                    files: HashMap::default(),
+                })
+            }
+        };
+        let mapping = process
+            .mappings
+            .iter()
+            .find(|v| address >= v.start && address < v.end)
+            .ok_or(-ENOENT)?;
+        let elf = process
+            .files
+            .entry(mapping.path.clone())
+            // Read the file through the root of the process, which may be in another mount namespace
+            .or_insert_with(|| ElfSymbols::load(&format!("/proc/{}/root{}", pid, mapping.path)))
+            .as_ref()
+            .map_err(|e| *e)?;
+        elf.lookup_file_offset(address - mapping.start + mapping.offset)
+            .ok_or(-ENOENT)
+    }
+}
+
+/// Write a resolved symbol into the guest memory. The name is truncated to fit in
+/// `name_size` with a terminating null, and the offset is written as u64 if `offset_out` isn't null.
+///
+/// Returns the length of the whole name
+fn write_symbol(
+    caller: &mut CallerType,
+    name: &str,
+    offset: u64,
+    name_buf: WasmPointer,
+    name_size: u32,
+    offset_out: WasmPointer,
+) -> i32 {
+    let memory = caller.get_memory().expect("Expected exported `memory`");
+    if name_size > 0 {
+        let copied = name.len().min(name_size as usize - 1);
+        let mut buf = name.as_bytes()[..copied].to_vec();
+        buf.push(0);
+        if let Err(err) = memory.write(&mut *caller, name_buf as usize, &buf) {
+            debug!("Failed to write wasm memory: {}", err);
+            return -EFAULT;
+        }
+    }
+    if offset_out != 0 {
+        if let Err(err) = memory.write(&mut *caller, offset_out as usize, &offset.to_le_bytes()) {
+            debug!("Failed to write wasm memory: {}", err);
+            return -EFAULT;
+        }
+    }
+    name.len() as i32
+}
+
+/// resolve a kernel address, like one in a `BPF_MAP_TYPE_STACK_TRACE` map, through `/proc/kallsyms`.
+///
+/// Returns the length of the symbol name, or a negative value if failed
+pub fn wasm_bpf_ksym_resolve(
+    mut caller: CallerType,
+    address: u64,
+    name_buf: WasmPointer,
+    name_size: u32,
+    offset_out: WasmPointer, // Allow null pointers
+) -> i32 {
+    let (name, offset) = match caller.data_mut().symbolizer.resolve_kernel(address) {
+        Ok((name, offset)) => (name.to_string(), offset),
+        Err(e) => return e,
+    };
+    write_symbol(&mut caller, &name, offset, name_buf, name_size, offset_out)
+}
+
+/// resolve a user address of the process `pid` through the symbol tables of its mapped ELF files.
+///
+/// Returns the length of the symbol name, or a negative value if failed
+pub fn wasm_bpf_usym_resolve(
+    mut caller: CallerType,
+    pid: i32,
+    address: u64,
+    name_buf: WasmPointer,
+    name_size: u32,
+    offset_out: WasmPointer, // Allow null pointers
+) -> i32 {
+    let (name, offset) = match caller.data_mut().symbolizer.resolve_user(pid, address) {
+        Ok((name, offset)) => (name.to_string(), offset),
+        Err(e) => return e,
+    };
+    write_symbol(&mut caller, &name, offset, name_buf, name_size, offset_out)
+}
+
+/// drop the cached mappings and symbols of the process `pid`, like after it calls `exec`
+pub fn wasm_bpf_usym_cache_clear(mut caller: CallerType, pid: i32) -> i32 {
+    caller.data_mut().symbolizer.processes.remove(&pid);
+    0
+}
@@ -0,0 +1,162 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::sync::{Arc, Mutex, Weak};
+
+use libbpf_rs::{
+    libbpf_sys::{
+        bpf_tc_hook, bpf_tc_hook_create, bpf_tc_hook_destroy, size_t, BPF_TC_EGRESS, BPF_TC_INGRESS,
+    },
+    TcHook, TcHookBuilder, TC_EGRESS, TC_INGRESS,
+};
+use log::debug;
+
+use super::{libbpf_errno, EEXIST};
+
+/// The clsact qdiscs created by the runtime, shared by the attachments on the same interface
+static CREATED_QDISCS: Mutex<Vec<(i32, Weak<ClsactQdisc>)>> = Mutex::new(Vec::new());
+
+/// A parsed tc attach target, in the form of `interface:ingress` or `interface:egress`,
+/// optionally followed by `:priority` and `:handle`
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct TcTarget<'a> {
+    pub interface: &'a str,
+    pub ingress: bool,
+    pub priority: u32,
+    pub handle: u32,
+}
+
+impl<'a> TcTarget<'a> {
+    /// Parse the attach target. Numbers may be decimal or hex with `0x`
+    pub(crate) fn parse(target: &'a str) -> Option<Self> {
+        let parse_u32 = |v: &str| match v.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => v.parse().ok(),
+        };
+        let mut parts = target.split(':');
+        let interface = parts.next().filter(|v| !v.is_empty())?;
+        let ingress = match parts.next()? {
+            "ingress" => true,
+            "egress" => false,
+            _ => return None,
+        };
+        let priority = parts.next().map_or(Some(0), parse_u32)?;
+        let handle = parts.next().map_or(Some(0), parse_u32)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            interface,
+            ingress,
+            priority,
+            handle,
+        })
+    }
+}
+
+/// The clsact qdisc of an interface created by the runtime, destroyed when dropped
+struct ClsactQdisc {
+    ifindex: i32,
+}
+
+impl ClsactQdisc {
+    fn hook(ifindex: i32) -> bpf_tc_hook {
+        bpf_tc_hook {
+            sz: std::mem::size_of::<bpf_tc_hook>() as size_t,
+            ifindex,
+            // Both attach points stand for the qdisc itself
+            attach_point: BPF_TC_INGRESS | BPF_TC_EGRESS,
+            ..Default::default()
+        }
+    }
+
+    /// Get the qdisc created by the runtime on the interface, or create it.
+    /// Returns `None` if it was created by others, which is kept after detaching
+    fn get_or_create(ifindex: i32) -> Result<Option<Arc<Self>>, i32> {
+        let mut created = CREATED_QDISCS.lock().unwrap();
+        created.retain(|(_, v)| v.strong_count() > 0);
+        if let Some(qdisc) = created
+            .iter()
+            .find(|(v, _)| *v == ifindex)
+            .and_then(|(_, v)| v.upgrade())
+        {
+            return Ok(Some(qdisc));
+        }
+        let mut hook = Self::hook(ifindex);
+        // SAFETY: the hook lives through the call
+        let err = unsafe { bpf_tc_hook_create(&mut hook) };
+        match err {
+            0 => {
+                let qdisc = Arc::new(Self { ifindex });
+                created.push((ifindex, Arc::downgrade(&qdisc)));
+                Ok(Some(qdisc))
+            }
+            v if v == -EEXIST => Ok(None),
+            v => {
+                debug!("Failed to create clsact qdisc: {}", v);
+                Err(v)
+            }
+        }
+    }
+}
+
+impl Drop for ClsactQdisc {
+    fn drop(&mut self) {
+        let mut hook = Self::hook(self.ifindex);
+        // SAFETY: the hook lives through the call
+        let err = unsafe { bpf_tc_hook_destroy(&mut hook) };
+        if err != 0 {
+            debug!(
+                "Failed to destroy clsact qdisc of {}: {}",
+                self.ifindex, err
+            );
+        }
+    }
+}
+
+/// A tc program attached to the clsact qdisc of an interface.
+/// The filter is detached when it's dropped. The qdisc is destroyed after the last
+/// attachment on it only if it was created by the runtime, since others may use it
+pub struct TcAttachment {
+    hook: TcHook,
+    _qdisc: Option<Arc<ClsactQdisc>>,
+}
+
+impl Drop for TcAttachment {
+    fn drop(&mut self) {
+        if let Err(e) = self.hook.detach() {
+            debug!("Failed to detach tc program: {}", e);
+        }
+    }
+}
+
+/// Attach a tc program to the clsact qdisc of the interface, creating the qdisc if needed.
+/// Priority and handle are assigned by the kernel if they are zero
+pub(crate) fn attach_tc(
+    prog_fd: i32,
+    ifindex: i32,
+    target: &TcTarget,
+) -> Result<TcAttachment, i32> {
+    let qdisc = ClsactQdisc::get_or_create(ifindex)?;
+    let mut hook = TcHookBuilder::new()
+        .fd(prog_fd)
+        .ifindex(ifindex)
+        .handle(target.handle)
+        .priority(target.priority)
+        .hook(if target.ingress {
+            TC_INGRESS
+        } else {
+            TC_EGRESS
+        });
+    // The hook with the handle and the priority assigned by the kernel is needed for detaching
+    let hook = hook.attach().map_err(|e| {
+        debug!("Failed to attach tc program: {}", e);
+        libbpf_errno(&e)
+    })?;
+    Ok(TcAttachment {
+        hook,
+        _qdisc: qdisc,
+    })
+}
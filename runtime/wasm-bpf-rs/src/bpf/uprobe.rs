@@ -0,0 +1,177 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::{ffi::CString, path::Path, ptr::NonNull};
+
+use libbpf_rs::{
+    libbpf_sys::{bpf_program, bpf_program__attach_uprobe_opts, bpf_uprobe_opts, size_t},
+    Link,
+};
+use log::debug;
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+
+use super::{EINVAL, ENOENT};
+
+/// A parsed uprobe attach target, in the form of `binary`, `binary:symbol`
+/// or `binary:symbol+offset`
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct UprobeTarget<'a> {
+    pub binary: &'a str,
+    pub symbol: Option<&'a str>,
+    pub offset: u64,
+}
+
+impl<'a> UprobeTarget<'a> {
+    /// Parse the attach target. The offset may be decimal or hex with `0x`
+    pub(crate) fn parse(target: &'a str) -> Option<Self> {
+        let (binary, symbol) = match target.rsplit_once(':') {
+            Some((binary, symbol)) => (binary, Some(symbol)),
+            None => (target, None),
+        };
+        if binary.is_empty() {
+            return None;
+        }
+        let (symbol, offset) = match symbol.map(|v| v.split_once('+').unwrap_or((v, ""))) {
+            None => (None, 0),
+            Some((symbol, "")) => (Some(symbol), 0),
+            Some((symbol, offset)) => {
+                let offset = match offset.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+                    None => offset.parse().ok()?,
+                };
+                (Some(symbol), offset)
+            }
+        };
+        if symbol == Some("") {
+            return None;
+        }
+        Some(Self {
+            binary,
+            symbol,
+            offset,
+        })
+    }
+}
+
+/// A parsed USDT attach target, in the form of `binary:provider:name`
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct UsdtTarget<'a> {
+    pub binary: &'a str,
+    pub provider: &'a str,
+    pub name: &'a str,
+}
+
+impl<'a> UsdtTarget<'a> {
+    /// Parse the attach target
+    pub(crate) fn parse(target: &'a str) -> Option<Self> {
+        let mut parts = target.rsplitn(3, ':');
+        let name = parts.next()?;
+        let provider = parts.next()?;
+        let binary = parts.next()?;
+        if [binary, provider, name].iter().any(|v| v.is_empty()) {
+            return None;
+        }
+        Some(Self {
+            binary,
+            provider,
+            name,
+        })
+    }
+}
+
+/// Resolve the offset of a function symbol in the file of an ELF binary,
+/// which is what uprobes take. Both `.symtab` and `.dynsym` are searched
+pub(crate) fn resolve_symbol_offset(binary: &Path, symbol: &str) -> Result<u64, i32> {
+    let data = std::fs::read(binary).map_err(|e| {
+        debug!("Failed to read `{}`: {}", binary.display(), e);
+        -e.raw_os_error().unwrap_or(ENOENT)
+    })?;
+    let file = object::File::parse(&*data).map_err(|e| {
+        debug!("Failed to parse ELF `{}`: {}", binary.display(), e);
+        -EINVAL
+    })?;
+    let address = file
+        .symbols()
+        .chain(file.dynamic_symbols())
+        .find(|v| v.kind() == SymbolKind::Text && !v.is_undefined() && v.name() == Ok(symbol))
+        .map(|v| v.address())
+        .ok_or_else(|| {
+            debug!("No function `{}` found in `{}`", symbol, binary.display());
+            -ENOENT
+        })?;
+    // The symbol holds the virtual address, which is translated by the segment containing it
+    for segment in file.segments() {
+        let (file_offset, file_size) = segment.file_range();
+        if address >= segment.address() && address < segment.address() + file_size {
+            return Ok(address - segment.address() + file_offset);
+        }
+    }
+    debug!(
+        "`{}` at {:#x} isn't in any segment of `{}`",
+        symbol,
+        address,
+        binary.display()
+    );
+    Err(-ENOENT)
+}
+
+/// Attach a uprobe program to the target. `pid` is -1 for all processes
+pub(crate) fn attach_uprobe(
+    prog: NonNull<bpf_program>,
+    target: &str,
+    pid: i32,
+    cookie: u64,
+    offset: u64,
+    retprobe: bool,
+) -> Result<Link, i32> {
+    let target = UprobeTarget::parse(target).ok_or_else(|| {
+        debug!("Invalid uprobe target `{}`", target);
+        -EINVAL
+    })?;
+    let symbol_offset = match target.symbol {
+        Some(symbol) => resolve_symbol_offset(Path::new(target.binary), symbol)?,
+        None => 0,
+    };
+    let Some(func_offset) = symbol_offset
+        .checked_add(target.offset)
+        .and_then(|v| v.checked_add(offset))
+    else {
+        debug!(
+            "The offset of uprobe target {:?} overflows with {:#x}",
+            target, offset
+        );
+        return Err(-EINVAL);
+    };
+    debug!(
+        "Attaching uprobe to {:?} at {:#x}, pid={}",
+        target, func_offset, pid
+    );
+    let binary = CString::new(target.binary).map_err(|_| -EINVAL)?;
+    let opts = bpf_uprobe_opts {
+        sz: std::mem::size_of::<bpf_uprobe_opts>() as size_t,
+        bpf_cookie: cookie,
+        retprobe,
+        ..Default::default()
+    };
+    // SAFETY: the string and the options live through the call
+    let link = unsafe {
+        bpf_program__attach_uprobe_opts(
+            prog.as_ptr(),
+            pid,
+            binary.as_ptr(),
+            func_offset as size_t,
+            &opts,
+        )
+    };
+    match NonNull::new(link) {
+        // SAFETY: the link is just created by libbpf
+        Some(v) => Ok(unsafe { Link::from_ptr(v) }),
+        None => {
+            let e = errno::errno();
+            debug!("Failed to attach uprobe: {}", e);
+            Err(-e.0)
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+use std::fmt::Display;
+
+/// A bpf object failed to be opened or loaded by the wasm program.
+///
+/// It's attached to the error returned by `WasmBpfEntryFuncWrapper::run` if the wasm program fails
+/// after that without attempting to open or load another object, and can be found by
+/// `anyhow::Error::downcast_ref`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BpfLoadError {
+    /// What failed, like `Failed to load bpf object: Invalid argument (os error 22)`
+    pub message: String,
+    /// The negative errno of the failure, which is also returned to the guest
+    pub errno: i32,
+    /// The libbpf output during the attempt, including the verifier log
+    pub log: String,
+}
+
+impl Display for BpfLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.log.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}\n{}", self.message, self.log.trim_end())
+        }
+    }
+}
+
+impl std::error::Error for BpfLoadError {}
@@ -7,6 +7,7 @@ mod bpf;
 mod state;
 mod utils;
 
+pub mod error;
 pub mod handle;
 pub mod pipe;
 pub mod runner;
@@ -15,6 +16,7 @@ use std::{sync::mpsc, thread::JoinHandle};
 
 use anyhow::anyhow;
 use handle::WasmProgramHandle;
+pub use libbpf_rs::PrintLevel;
 use runner::WasmBpfModuleRunner;
 use state::AppState;
 use wasi_common::WasiFile;
@@ -34,6 +36,12 @@ pub struct Config {
     pub stdout: Box<dyn WasiFile>,
     /// stderr file for sending error to the host
     pub stderr: Box<dyn WasiFile>,
+    /// Whether to attach the struct_ops maps of bpf objects when they are loaded
+    pub auto_attach_struct_ops: bool,
+    /// The lowest level of libbpf output kept when a bpf object fails to be loaded
+    pub libbpf_log_level: PrintLevel,
+    /// Where the values decoded from BTF by the wasm program are sent, if set
+    pub json_sender: Option<mpsc::Sender<serde_json::Value>>,
 }
 
 impl Default for Config {
@@ -44,6 +52,9 @@ impl Default for Config {
             stdin: Box::new(stdio::stdin()),
             stdout: Box::new(stdio::stdout()),
             stderr: Box::new(stdio::stderr()),
+            auto_attach_struct_ops: false,
+            libbpf_log_level: PrintLevel::Info,
+            json_sender: None,
         }
     }
 }
@@ -73,8 +84,26 @@ impl Config {
             stdin,
             stdout,
             stderr,
+            auto_attach_struct_ops: false,
+            libbpf_log_level: PrintLevel::Info,
+            json_sender: None,
         }
     }
+    /// Set whether to attach the struct_ops maps of bpf objects when they are loaded.
+    /// Otherwise they are attached by `wasm_bpf_struct_ops_attach`
+    pub fn set_auto_attach_struct_ops(&mut self, auto_attach_struct_ops: bool) {
+        self.auto_attach_struct_ops = auto_attach_struct_ops;
+    }
+    /// Set the lowest level of libbpf output kept when a bpf object fails to be loaded,
+    /// which is returned to the wasm program by `wasm_bpf_last_error`
+    pub fn set_libbpf_log_level(&mut self, libbpf_log_level: PrintLevel) {
+        self.libbpf_log_level = libbpf_log_level;
+    }
+    /// Set where to send the values decoded by `wasm_bpf_map_value_to_json` and `wasm_bpf_btf_to_json`,
+    /// so that the embedder receives the events of the wasm program as JSON values
+    pub fn set_json_sender(&mut self, json_sender: mpsc::Sender<serde_json::Value>) {
+        self.json_sender = Some(json_sender);
+    }
 }
 
 /// Run a Wasm eBPF module with args
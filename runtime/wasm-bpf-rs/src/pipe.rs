@@ -54,10 +54,10 @@ impl<W: Write + Any + Send + Sync> WasiFile for ReadableWritePipe<W> {
 }
 
 impl<W: Write> ReadableWritePipe<W> {
-    pub fn borrow(&self) -> std::sync::RwLockWriteGuard<W> {
+    pub fn borrow(&self) -> std::sync::RwLockWriteGuard<'_, W> {
         RwLock::write(&self.buf).unwrap()
     }
-    pub fn get_read_lock(&self) -> std::sync::RwLockReadGuard<W> {
+    pub fn get_read_lock(&self) -> std::sync::RwLockReadGuard<'_, W> {
         self.buf.read().unwrap()
     }
     pub fn new(inner: W) -> Self {
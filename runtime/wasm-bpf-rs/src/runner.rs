@@ -6,12 +6,38 @@ use wasmtime::{Engine, IntoFunc, Linker, Module, Store, TypedFunc};
 use wasmtime_wasi::WasiCtxBuilder;
 
 use crate::add_bind_function_with_module;
-use crate::bpf::attach::wasm_attach_bpf_program;
+use crate::bpf::abi::{
+    host_function_level, wasm_bpf_get_abi_version, wasm_bpf_get_capabilities,
+    wasm_bpf_get_features, ABI_VERSION,
+};
+use crate::bpf::attach::{
+    wasm_attach_bpf_program, wasm_bpf_link_detach, wasm_bpf_program_attach,
+    wasm_bpf_program_attach_opts,
+};
+use crate::bpf::btf_json::{wasm_bpf_btf_to_json, wasm_bpf_map_value_to_json};
 use crate::bpf::close::wasm_close_bpf_object;
-use crate::bpf::fd_by_name::wasm_bpf_map_fd_by_name;
-use crate::bpf::load::wasm_load_bpf_object;
+use crate::bpf::configure::{
+    wasm_bpf_map_set_max_entries, wasm_bpf_program_set_attach_target, wasm_bpf_program_set_autoload,
+};
+use crate::bpf::fd_by_name::{
+    wasm_bpf_map_fd_by_name, wasm_bpf_program_fd_by_id, wasm_bpf_program_fd_by_name,
+};
+use crate::bpf::global_var::{wasm_bpf_global_var_get, wasm_bpf_global_var_set};
+use crate::bpf::iter::{wasm_bpf_iter_close, wasm_bpf_iter_create, wasm_bpf_iter_read};
+use crate::bpf::libbpf_log::{wasm_bpf_last_errno, wasm_bpf_last_error};
+use crate::bpf::load::{wasm_bpf_object_load, wasm_load_bpf_object, wasm_open_bpf_object};
 use crate::bpf::map_operate::wasm_bpf_map_operate;
-use crate::bpf::poll::wasm_bpf_buffer_poll;
+use crate::bpf::object_info::{wasm_bpf_object_maps, wasm_bpf_object_programs};
+use crate::bpf::perf_event::wasm_bpf_program_attach_perf_event;
+use crate::bpf::poll::{
+    wasm_bpf_buffer_lost_count, wasm_bpf_buffer_poll, wasm_bpf_buffer_poll_batch,
+    wasm_bpf_buffer_poll_multi, wasm_bpf_buffer_poll_with_cpu,
+};
+use crate::bpf::socket_filter::{wasm_bpf_socket_filter_open, wasm_bpf_socket_filter_read};
+use crate::bpf::struct_ops::wasm_bpf_struct_ops_attach;
+use crate::bpf::symbolize::{
+    wasm_bpf_ksym_resolve, wasm_bpf_usym_cache_clear, wasm_bpf_usym_resolve,
+};
 use crate::{
     add_bind_function, add_bind_function_with_module_and_name,
     bpf::wrapper_poll,
@@ -27,8 +53,15 @@ pub struct WasmBpfEntryFuncWrapper {
 
 impl WasmBpfEntryFuncWrapper {
     /// Run the wasm program from the entry function
-    pub fn run(self) -> anyhow::Result<()> {
-        self.func.call(self.store, ())
+    /// The error is attached with a `BpfLoadError` if the last attempt of opening or loading a
+    /// bpf object failed
+    pub fn run(mut self) -> anyhow::Result<()> {
+        self.func.call(&mut self.store, ()).map_err(|err| {
+            match self.store.data_mut().last_load_error.take() {
+                Some(load_error) => err.context(load_error),
+                None => err,
+            }
+        })
     }
 }
 /// This struct provides ability to parse and link the input wasm module
@@ -45,6 +78,10 @@ pub struct WasmBpfModuleRunner {
 
 impl WasmBpfModuleRunner {
     /// Create a runner.
+    ///
+    /// It fails if the wasm program imports host functions from the `wasm_bpf` module
+    /// which this runtime doesn't provide, like ones of a newer ABI version.
+    /// Custom host functions should be registered in other modules
     pub fn new(module_binary: &[u8], args: &[String], config: Config) -> anyhow::Result<Self> {
         let engine_config = wasmtime::Config::new()
             .epoch_interruption(true) // It must be enabled
@@ -62,10 +99,11 @@ impl WasmBpfModuleRunner {
             .with_context(|| anyhow!("Failed to pass arguments to Wasm program"))?
             .build();
         let (tx, rx) = mpsc::channel::<ProgramOperation>();
-        let mut store = Store::new(
-            &engine,
-            AppState::new(wasi, config.callback_export_name.clone(), rx),
-        );
+        let mut state = AppState::new(wasi, config.callback_export_name.clone(), rx);
+        state.auto_attach_struct_ops = config.auto_attach_struct_ops;
+        state.libbpf_log_level = config.libbpf_log_level;
+        state.json_sender = config.json_sender;
+        let mut store = Store::new(&engine, state);
 
         store.set_epoch_deadline(1);
         store.epoch_deadline_callback(move |v| {
@@ -83,6 +121,42 @@ impl WasmBpfModuleRunner {
         add_bind_function!(linker, wasm_bpf_buffer_poll)?;
         add_bind_function!(linker, wasm_bpf_map_fd_by_name)?;
         add_bind_function!(linker, wasm_bpf_map_operate)?;
+        add_bind_function!(linker, wasm_open_bpf_object)?;
+        add_bind_function!(linker, wasm_bpf_object_load)?;
+        add_bind_function!(linker, wasm_bpf_program_set_autoload)?;
+        add_bind_function!(linker, wasm_bpf_map_set_max_entries)?;
+        add_bind_function!(linker, wasm_bpf_global_var_get)?;
+        add_bind_function!(linker, wasm_bpf_global_var_set)?;
+        add_bind_function!(linker, wasm_bpf_buffer_poll_multi)?;
+        add_bind_function!(linker, wasm_bpf_buffer_poll_with_cpu)?;
+        add_bind_function!(linker, wasm_bpf_buffer_lost_count)?;
+        add_bind_function!(linker, wasm_bpf_buffer_poll_batch)?;
+        add_bind_function!(linker, wasm_bpf_program_attach)?;
+        add_bind_function!(linker, wasm_bpf_program_attach_opts)?;
+        add_bind_function!(linker, wasm_bpf_link_detach)?;
+        add_bind_function!(linker, wasm_bpf_socket_filter_open)?;
+        add_bind_function!(linker, wasm_bpf_socket_filter_read)?;
+        add_bind_function!(linker, wasm_bpf_program_attach_perf_event)?;
+        add_bind_function!(linker, wasm_bpf_ksym_resolve)?;
+        add_bind_function!(linker, wasm_bpf_usym_resolve)?;
+        add_bind_function!(linker, wasm_bpf_usym_cache_clear)?;
+        add_bind_function!(linker, wasm_bpf_iter_create)?;
+        add_bind_function!(linker, wasm_bpf_iter_read)?;
+        add_bind_function!(linker, wasm_bpf_iter_close)?;
+        add_bind_function!(linker, wasm_bpf_struct_ops_attach)?;
+        add_bind_function!(linker, wasm_bpf_program_set_attach_target)?;
+        add_bind_function!(linker, wasm_bpf_program_fd_by_name)?;
+        add_bind_function!(linker, wasm_bpf_program_fd_by_id)?;
+        add_bind_function!(linker, wasm_bpf_last_error)?;
+        add_bind_function!(linker, wasm_bpf_last_errno)?;
+        add_bind_function!(linker, wasm_bpf_get_abi_version)?;
+        add_bind_function!(linker, wasm_bpf_get_capabilities)?;
+        add_bind_function!(linker, wasm_bpf_get_features)?;
+        add_bind_function!(linker, wasm_bpf_object_programs)?;
+        add_bind_function!(linker, wasm_bpf_object_maps)?;
+        add_bind_function!(linker, wasm_bpf_map_value_to_json)?;
+        add_bind_function!(linker, wasm_bpf_btf_to_json)?;
+        check_wasm_bpf_imports(&linker, &mut store, &main_module)?;
 
         add_bind_function_with_module_and_name!(
             linker,
@@ -134,6 +208,61 @@ impl WasmBpfModuleRunner {
     }
 }
 
+/// Make sure the host functions imported from the `wasm_bpf` module are all provided, so that
+/// a program built for a newer ABI fails with a clear message instead of a link error
+fn check_wasm_bpf_imports(
+    linker: &Linker<AppState>,
+    store: &mut Store<AppState>,
+    module: &Module,
+) -> anyhow::Result<()> {
+    let missing: Vec<_> = module
+        .imports()
+        .filter(|v| v.module() == "wasm_bpf")
+        .filter(|v| linker.get(&mut *store, v.module(), v.name()).is_none())
+        .map(|v| v.name())
+        .collect();
+    match missing_imports_message(&missing) {
+        Some(message) => Err(anyhow!(message)),
+        None => Ok(()),
+    }
+}
+
+/// Describe the host functions missing in the runtime. Those known by the runtime are named
+/// with the capability or the ABI version providing them, and unknown ones are listed apart
+pub(crate) fn missing_imports_message(missing: &[&str]) -> Option<String> {
+    if missing.is_empty() {
+        return None;
+    }
+    let (known, unknown): (Vec<&str>, Vec<&str>) = missing
+        .iter()
+        .copied()
+        .partition(|v| host_function_level(v).is_some());
+    let mut messages = vec![];
+    if !known.is_empty() {
+        let known = known
+            .iter()
+            .map(|v| format!("`{}` of {}", v, host_function_level(v).unwrap()))
+            .collect::<Vec<_>>();
+        messages.push(format!(
+            "The wasm program imports {} from `wasm_bpf`, which this runtime doesn't provide",
+            known.join(", ")
+        ));
+    }
+    if !unknown.is_empty() {
+        let unknown = unknown
+            .iter()
+            .map(|v| format!("`{}`", v))
+            .collect::<Vec<_>>();
+        messages.push(format!(
+            "The wasm program imports unknown functions {} from `wasm_bpf`. \
+            They require a host ABI version newer than {}, the one of this runtime",
+            unknown.join(", "),
+            ABI_VERSION
+        ));
+    }
+    Some(messages.join(". "))
+}
+
 /// A trait which will be implemented on anyhow::Error to check whether the error indicates an non-zero exit code
 pub trait GetWasmExitCodeHelper {
     /// Returns `None` if the error doesn't indicate an non-zero exit code. Otherwise returns the exit code
@@ -4,46 +4,63 @@
 //! All rights reserved.
 //!
 use std::{
-    cell::{Ref, RefCell, RefMut},
-    collections::HashMap,
+    cell::{Cell, Ref, RefCell, RefMut},
+    collections::{HashMap, VecDeque},
     fs::File,
+    os::fd::OwnedFd,
+    ptr::NonNull,
     rc::Rc,
     sync::mpsc,
 };
 
-use libbpf_rs::{Link, Object, PerfBuffer, RingBuffer};
+use libbpf_rs::{libbpf_sys::bpf_object, Object, OpenObject, PrintLevel};
 use wasmtime::Caller;
 use wasmtime_wasi::WasiCtx;
 
-use crate::handle::ProgramOperation;
+use crate::{
+    bpf::{attach::Attachment, global_var::MmapedDatasec, symbolize::Symbolizer},
+    error::BpfLoadError,
+    handle::ProgramOperation,
+};
 
-use ouroboros::self_referencing;
 const FIRST_OBJECT_ID: u64 = 1;
+const FIRST_LINK_ID: i32 = 1;
 
-/// The callback of a ringbuffer poller
-pub type RingBufferCallback = Box<dyn Fn(&[u8]) -> i32>;
-#[self_referencing(pub_extras)]
-/// A helper container to hold a RingBuffer and its calback
-pub struct RingBufferContainer {
-    /// The callback
-    pub callback_func: RingBufferCallback,
-    #[borrows(callback_func)]
-    #[covariant]
-    /// The ringbuf
-    pub ringbuf: RingBuffer<'this>,
-}
-/// The callback of a perfbuffer poller
-pub type PerfBufferSampleCallback = Box<dyn Fn(i32, &[u8])>;
-#[self_referencing(pub_extras)]
-/// A helper container to hold a perfbuffer and its callback
-pub struct PerfBufferContainer {
-    /// The callback
-    pub callback_func: PerfBufferSampleCallback,
-    #[borrows(callback_func)]
-    #[covariant]
-    /// The perfbuffer
-    pub perfbuf: PerfBuffer<'this>,
+pub use self::containers::*;
+
+// The code generated by ouroboros transmutes references into pointers, and the callbacks
+// are only held to keep them alive for the borrowing buffers
+#[allow(clippy::useless_transmute, dead_code)]
+mod containers {
+    use libbpf_rs::{PerfBuffer, RingBuffer};
+    use ouroboros::self_referencing;
+
+    /// The callback of a ringbuffer poller, receiving the fd of the map and the record
+    pub type RingBufferCallback = Box<dyn Fn(i32, &[u8]) -> i32>;
+    #[self_referencing(pub_extras)]
+    /// A helper container to hold a RingBuffer and its calback
+    pub struct RingBufferContainer {
+        /// The callback
+        pub callback_func: RingBufferCallback,
+        #[borrows(callback_func)]
+        #[covariant]
+        /// The ringbuf
+        pub ringbuf: RingBuffer<'this>,
+    }
+    /// The callback of a perfbuffer poller
+    pub type PerfBufferSampleCallback = Box<dyn Fn(i32, &[u8])>;
+    #[self_referencing(pub_extras)]
+    /// A helper container to hold a perfbuffer and its callback
+    pub struct PerfBufferContainer {
+        /// The callback
+        pub callback_func: PerfBufferSampleCallback,
+        #[borrows(callback_func)]
+        #[covariant]
+        /// The perfbuffer
+        pub perfbuf: PerfBuffer<'this>,
+    }
 }
+
 /// The enum to hold two kinds of poller
 pub enum PollBufferImpl {
     /// The ringbuf
@@ -51,22 +68,56 @@ pub enum PollBufferImpl {
     /// The perfevent
     PerfEvent(PerfBufferContainer),
 }
+/// A record received by a poller, waiting to be delivered to the guest
+pub struct PolledRecord {
+    /// The fd of the map that the record comes from
+    pub map_fd: i32,
+    /// The cpu that the record comes from; -1 if the map doesn't tell it, like ringbufs
+    pub cpu: i32,
+    /// The record
+    pub data: Vec<u8>,
+}
 /// The container of a poller implementation and its result container
-/// The result container will be used to store the result that the sampling callback receives
+/// The result container will be used to queue the records that the sampling callback receives
 /// since it's a shared Rc, so we can write the result in the callback regardless of the ownership
 pub struct PollBuffer {
     /// The implementation
     pub inner: PollBufferImpl,
-    /// The result container
-    pub result_container: Rc<RefCell<Option<Vec<u8>>>>,
+    /// The result container; Records not delivered to the guest yet will be kept here
+    pub result_container: Rc<RefCell<VecDeque<PolledRecord>>>,
+    /// The number of samples lost since the poller was created; Only perfbuffers lose samples
+    pub lost_samples: Rc<Cell<u64>>,
+}
+/// A bpf object opened but not loaded yet
+pub(crate) struct WrapperOpenObject {
+    /// The opened object
+    pub object: OpenObject,
+    /// The libbpf pointer of the object, kept since libbpf-rs only gives it out by
+    /// consuming the `OpenObject`
+    pub ptr: NonNull<bpf_object>,
 }
 /// A `Program`, holding a bpf Object and a poller
 pub struct WrapperObject {
     // Put Object in a Rc<RefCell<T>> to avoid holding a reference to WrapperObject
     /// The ebpf Object
     pub object: Rc<RefCell<Object>>,
-    /// The poller; It will be set when the first time to call the sampling function
-    pub poll_buffer: Option<PollBuffer>,
+    /// The pollers, indexed by the sorted fds of the maps they poll;
+    /// A poller will be created when the first time to poll its maps
+    pub poll_buffers: HashMap<Vec<i32>, PollBuffer>,
+    /// The datasec maps mmaped for accessing global variables, indexed by map fd
+    pub datasec_mmaps: HashMap<i32, MmapedDatasec>,
+    /// The links of the attached programs, indexed by link handle;
+    /// They are detached when the object is closed
+    pub links: HashMap<i32, Attachment>,
+    /// The iterators created from the links of iterator programs, indexed by handle
+    pub iters: HashMap<i32, File>,
+}
+
+impl Drop for WrapperObject {
+    fn drop(&mut self) {
+        // Detach the programs before the object closes their fds
+        self.links.clear();
+    }
 }
 
 impl WrapperObject {
@@ -75,11 +126,11 @@ impl WrapperObject {
         self.object.clone()
     }
     /// Get a reference to the Object
-    pub fn get_object(&self) -> Ref<Object> {
+    pub fn get_object(&self) -> Ref<'_, Object> {
         self.object.borrow()
     }
     /// Get a mutable reference to the Object
-    pub fn get_object_mut(&self) -> RefMut<Object> {
+    pub fn get_object_mut(&self) -> RefMut<'_, Object> {
         self.object.borrow_mut()
     }
 }
@@ -89,8 +140,14 @@ pub struct AppState {
     pub(crate) wasi: WasiCtx,
     pub(crate) next_object_id: u64,
     pub(crate) object_map: HashMap<u64, WrapperObject>,
-    pub(crate) opened_files: Vec<File>,
-    pub(crate) opened_links: Vec<Link>,
+    pub(crate) open_object_map: HashMap<u64, WrapperOpenObject>,
+    pub(crate) next_link_id: i32,
+    pub(crate) symbolizer: Symbolizer,
+    pub(crate) auto_attach_struct_ops: bool,
+    pub(crate) program_fds_by_id: HashMap<u32, OwnedFd>,
+    pub(crate) libbpf_log_level: PrintLevel,
+    pub(crate) last_load_error: Option<BpfLoadError>,
+    pub(crate) json_sender: Option<mpsc::Sender<serde_json::Value>>,
     pub(crate) callback_func_name: String,
     pub(crate) wrapper_called: bool,
     pub(crate) operation_rx: mpsc::Receiver<ProgramOperation>,
@@ -107,13 +164,23 @@ impl AppState {
             wasi,
             next_object_id: FIRST_OBJECT_ID,
             object_map: HashMap::default(),
-            opened_files: vec![],
-            opened_links: vec![],
+            open_object_map: HashMap::default(),
+            next_link_id: FIRST_LINK_ID,
+            symbolizer: Symbolizer::default(),
+            auto_attach_struct_ops: false,
+            program_fds_by_id: HashMap::default(),
+            libbpf_log_level: PrintLevel::Info,
+            last_load_error: None,
+            json_sender: None,
             callback_func_name,
             wrapper_called: false,
             operation_rx,
         }
     }
+    /// Get the libbpf pointer of an opened but not loaded object
+    pub(crate) fn open_object_ptr(&self, id: u64) -> Option<NonNull<bpf_object>> {
+        self.open_object_map.get(&id).map(|v| v.ptr)
+    }
 }
 
 pub(crate) type CallerType<'a> = Caller<'a, AppState>;
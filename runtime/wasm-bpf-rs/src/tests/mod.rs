@@ -1,16 +1,38 @@
 //! This module contains tests for the runtime.
 //!
 use flexi_logger::Logger;
+use libbpf_rs::libbpf_sys::{
+    bpf_attach_type, bpf_insn, bpf_link_create, bpf_map_create, bpf_map_create_opts, bpf_prog_load,
+    bpf_prog_load_opts, bpf_prog_query, bpf_prog_type, btf__add_array, btf__add_datasec,
+    btf__add_datasec_var_info, btf__add_field, btf__add_int, btf__add_ptr, btf__add_struct,
+    btf__add_var, btf__free, btf__new_empty, btf__raw_data, libbpf_find_vmlinux_btf_id,
+    BPF_CGROUP_INET_INGRESS, BPF_F_NO_PREALLOC, BPF_MAP_TYPE_ARRAY, BPF_MAP_TYPE_HASH,
+    BPF_MAP_TYPE_LPM_TRIE, BPF_MAP_TYPE_SOCKMAP, BPF_PROG_TYPE_SK_MSG, BPF_PROG_TYPE_SOCKET_FILTER,
+    BPF_PROG_TYPE_TRACING, BPF_SK_MSG_VERDICT, BPF_TRACE_ITER, BTF_INT_CHAR, BTF_INT_SIGNED,
+};
 
+use crate::bpf::attach::{attach_to_map, interface_index};
+use crate::bpf::btf_json::BtfDecoder;
+use crate::bpf::iter::create_iter;
+use crate::bpf::map_operate::{lookup_batch_fallback, update_batch_fallback};
+use crate::bpf::perf_event::{open_perf_event, parse_cpu_list};
+use crate::bpf::socket_filter::PacketSocket;
+use crate::bpf::symbolize::{parse_proc_maps, Mapping, SymbolTable, Symbolizer};
+use crate::bpf::tc::TcTarget;
+use crate::bpf::uprobe::{resolve_symbol_offset, UprobeTarget, UsdtTarget};
+use crate::error::BpfLoadError;
 use crate::handle::WasmProgramHandle;
 use crate::pipe::ReadableWritePipe;
-use crate::runner::GetWasmExitCodeHelper;
+use crate::runner::{missing_imports_message, GetWasmExitCodeHelper};
 use crate::state::CallerType;
 
 use super::*;
+use std::ffi::CString;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
 use std::path::PathBuf;
+use std::ptr::NonNull;
 use std::thread;
 use std::time::Duration;
 
@@ -51,7 +73,7 @@ fn test_example_and_wait(name: &str, config: Config, wait_policy: WaitPolicy) {
                 panic!("Wasm program exited abnormally: exit code = {}", exit_code);
             }
             // It was trapped. Right?
-            println!("{}", e.to_string());
+            println!("{}", e);
         }
     }
 }
@@ -179,7 +201,7 @@ fn test_pause_and_resume_wasm_program() {
 #[test]
 fn test_custom_host_function() {
     let module_binary = std::fs::read(get_test_file_path("custom_host_func.wasm")).unwrap();
-    let args = vec!["test".to_string()];
+    let args = ["test".to_string()];
     let mut runner =
         WasmBpfModuleRunner::new(&module_binary[..], &args[..], Config::default()).unwrap();
     runner
@@ -203,7 +225,7 @@ fn very_long_host_func() {
 fn test_interruption_in_host_function() {
     Logger::try_with_str("debug").unwrap().start().unwrap();
     let module_binary = std::fs::read(get_test_file_path("interruption_in_hostfunc.wasm")).unwrap();
-    let args = vec!["test".to_string()];
+    let args = ["test".to_string()];
 
     let (tx, rx) = mpsc::channel::<WasmProgramHandle>();
     std::thread::spawn(move || {
@@ -224,7 +246,7 @@ fn test_interruption_in_host_function() {
 #[test]
 fn test_interruption_in_wasm_callback() {
     let module_binary = std::fs::read(get_test_file_path("interruption_in_callback.wasm")).unwrap();
-    let args = vec!["test".to_string()];
+    let args = ["test".to_string()];
     let (handle, _) =
         run_wasm_bpf_module_async(&module_binary[..], &args[..], Config::default()).unwrap();
     std::thread::sleep(Duration::from_secs(2));
@@ -258,3 +280,2017 @@ fn test_exit_code_of_interruped_wasm_program() {
         WaitPolicy::WaitUntilTimedOut(2),
     );
 }
+
+/// Where the bpf object will be placed in the memory of a wat guest
+const WAT_BPF_OBJECT_OFFSET: usize = 4096;
+
+/// Build a guest from wat, with `bootstrap.bpf.o` placed at `WAT_BPF_OBJECT_OFFSET` of its memory.
+/// `$obj_size` will be a global holding the size of the object.
+/// The guest is expected to trap if anything went wrong
+fn build_wat_guest_with_bpf_object(module_fields: &str) -> Vec<u8> {
+    let object = std::fs::read(get_test_file_path("bootstrap.bpf.o")).unwrap();
+    build_wat_guest_with_object(module_fields, &object)
+}
+
+/// Build a guest from wat like `build_wat_guest_with_bpf_object`, with the given bpf object
+fn build_wat_guest_with_object(module_fields: &str, object: &[u8]) -> Vec<u8> {
+    let escaped: String = object.iter().map(|v| format!("\\{:02x}", v)).collect();
+    let pages = (WAT_BPF_OBJECT_OFFSET + object.len()) / 65536 + 2;
+    let wat = format!(
+        r#"(module
+            {module_fields}
+            (memory (export "memory") {pages})
+            (global $obj_size i32 (i32.const {size}))
+            (data (i32.const {offset}) "{escaped}"))"#,
+        size = object.len(),
+        offset = WAT_BPF_OBJECT_OFFSET,
+    );
+    wat::parse_str(wat).unwrap()
+}
+
+fn run_wat_guest_with_bpf_object(module_fields: &str) -> anyhow::Result<()> {
+    let object = std::fs::read(get_test_file_path("bootstrap.bpf.o")).unwrap();
+    run_wat_guest_with_object(module_fields, &object)
+}
+
+fn run_wat_guest_with_object(module_fields: &str, object: &[u8]) -> anyhow::Result<()> {
+    let module_binary = build_wat_guest_with_object(module_fields, object);
+    let args = ["test".to_string()];
+    WasmBpfModuleRunner::new(&module_binary[..], &args[..], Config::default())?
+        .into_engine_and_entry_func()?
+        .1
+        .run()
+}
+
+#[test]
+fn test_open_configure_and_load_bpf_object() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_open_bpf_object" (func $open (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_object_load" (func $load (param i64) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_program_set_autoload" (func $set_autoload (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_set_max_entries" (func $set_max_entries (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_fd_by_name" (func $map_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_close_bpf_object" (func $close (param i64) (result i32)))
+        (data (i32.const 16) "handle_exit\00")
+        (data (i32.const 32) "exec_start\00")
+        (data (i32.const 48) "no_such_map\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local.set $obj (call $open (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            ;; Maps can't be looked up before the object is loaded
+            (if (i32.ge_s (call $map_fd (local.get $obj) (i32.const 32)) (i32.const 0)) (then unreachable))
+            (if (call $set_autoload (local.get $obj) (i32.const 16) (i32.const 0)) (then unreachable))
+            (if (call $set_max_entries (local.get $obj) (i32.const 32) (i32.const 1024)) (then unreachable))
+            (if (i32.eqz (call $set_max_entries (local.get $obj) (i32.const 48) (i32.const 1024))) (then unreachable))
+            (if (call $load (local.get $obj)) (then unreachable))
+            ;; An object can only be loaded once
+            (if (i32.eqz (call $load (local.get $obj))) (then unreachable))
+            (if (i32.lt_s (call $map_fd (local.get $obj) (i32.const 32)) (i32.const 0)) (then unreachable))
+            (if (call $close (local.get $obj)) (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_access_global_variables() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_open_bpf_object" (func $open (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_object_load" (func $load (param i64) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_global_var_get" (func $get (param i64 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_global_var_set" (func $set (param i64 i32 i32 i32) (result i32)))
+        (data (i32.const 16) "min_duration_ns\00")
+        (data (i32.const 48) "no_such_var\00")
+        (func $check_value (param $obj i64)
+            (i64.store (i32.const 72) (i64.const 0))
+            (if (call $get (local.get $obj) (i32.const 16) (i32.const 72) (i32.const 8)) (then unreachable))
+            (if (i64.ne (i64.load (i32.const 72)) (i64.const 0x123456789)) (then unreachable)))
+        (func (export "_start")
+            (local $obj i64)
+            (local.set $obj (call $open (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (i64.store (i32.const 64) (i64.const 0x123456789))
+            (if (call $set (local.get $obj) (i32.const 16) (i32.const 64) (i32.const 8)) (then unreachable))
+            (call $check_value (local.get $obj))
+            ;; Larger than the variable
+            (if (i32.eqz (call $set (local.get $obj) (i32.const 16) (i32.const 64) (i32.const 16))) (then unreachable))
+            (if (i32.eqz (call $get (local.get $obj) (i32.const 48) (i32.const 64) (i32.const 8))) (then unreachable))
+            (if (call $load (local.get $obj)) (then unreachable))
+            (call $check_value (local.get $obj))
+            ;; .rodata is frozen after loading
+            (if (i32.eqz (call $set (local.get $obj) (i32.const 16) (i32.const 64) (i32.const 8))) (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+fn spawn_exec_events() {
+    for _ in 0..8 {
+        std::process::Command::new("true").status().unwrap();
+    }
+}
+
+#[test]
+fn test_poll_delivers_every_record() {
+    let module_binary = build_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_attach_bpf_program" (func $attach (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_fd_by_name" (func $map_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_buffer_poll" (func $poll (param i64 i32 i32 i32 i32 i32 i32) (result i32)))
+        (import "test" "spawn_exec_events" (func $spawn_exec_events))
+        (data (i32.const 16) "handle_exec\00")
+        (data (i32.const 32) "rb\00")
+        (global $count (mut i32) (i32.const 0))
+        (table (export "__indirect_function_table") 2 funcref)
+        (elem (i32.const 1) $on_sample)
+        (func $on_sample (param i32 i32 i32) (result i32)
+            (global.set $count (i32.add (global.get $count) (i32.const 1)))
+            (i32.const 0))
+        (func (export "_start")
+            (local $obj i64)
+            (local $fd i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (if (call $attach (local.get $obj) (i32.const 16) (i32.const 0)) (then unreachable))
+            (local.set $fd (call $map_fd (local.get $obj) (i32.const 32)))
+            (call $spawn_exec_events)
+            (if (call $poll (local.get $obj) (local.get $fd) (i32.const 1) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 1000))
+                (then unreachable))
+            ;; All events of the spawned processes should be received by a single poll
+            (if (i32.lt_u (global.get $count) (i32.const 8)) (then unreachable))
+            ;; A callback out of the table is reported
+            (call $spawn_exec_events)
+            (if (i32.ne (call $poll (local.get $obj) (local.get $fd) (i32.const 5) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 1000))
+                    (i32.const -22))
+                (then unreachable)))
+        "#,
+    );
+    let args = ["test".to_string()];
+    let mut runner =
+        WasmBpfModuleRunner::new(&module_binary[..], &args[..], Config::default()).unwrap();
+    runner
+        .register_host_function("test", "spawn_exec_events", spawn_exec_events)
+        .unwrap();
+    let (_, wrapper) = runner.into_engine_and_entry_func().unwrap();
+    wrapper.run().unwrap();
+}
+
+#[test]
+fn test_poll_multiple_buffers() {
+    let module_binary = build_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_attach_bpf_program" (func $attach (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_fd_by_name" (func $map_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_buffer_poll" (func $poll (param i64 i32 i32 i32 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_buffer_poll_multi" (func $poll_multi (param i64 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+        (import "test" "spawn_exec_events" (func $spawn_exec_events))
+        (data (i32.const 16) "handle_exec\00")
+        (data (i32.const 32) "rb\00")
+        (data (i32.const 48) "exec_start\00")
+        (global $rb_fd (mut i32) (i32.const 0))
+        (global $count (mut i32) (i32.const 0))
+        (table (export "__indirect_function_table") 3 funcref)
+        (elem (i32.const 1) $on_sample $on_sample_with_fd)
+        (func $on_sample (param i32 i32 i32) (result i32)
+            (i32.const 0))
+        (func $on_sample_with_fd (param i32 i32 i32 i32) (result i32)
+            (if (i32.ne (local.get 1) (global.get $rb_fd)) (then unreachable))
+            (global.set $count (i32.add (global.get $count) (i32.const 1)))
+            (i32.const 0))
+        (func (export "_start")
+            (local $obj i64)
+            (local $hash_fd i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (if (call $attach (local.get $obj) (i32.const 16) (i32.const 0)) (then unreachable))
+            (global.set $rb_fd (call $map_fd (local.get $obj) (i32.const 32)))
+            (local.set $hash_fd (call $map_fd (local.get $obj) (i32.const 48)))
+            (if (call $poll (local.get $obj) (global.get $rb_fd) (i32.const 1) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 0))
+                (then unreachable))
+            ;; Each map gets its own poller, so a hash map can't be polled
+            (if (i32.eqz (call $poll (local.get $obj) (local.get $hash_fd) (i32.const 1) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 0)))
+                (then unreachable))
+            (i32.store (i32.const 64) (global.get $rb_fd))
+            (i32.store (i32.const 68) (local.get $hash_fd))
+            (if (i32.ne (call $poll_multi (local.get $obj) (i32.const 64) (i32.const 0x40000001) (i32.const 2) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 0)) (i32.const -22))
+                (then unreachable))
+            (if (i32.eqz (call $poll_multi (local.get $obj) (i32.const 64) (i32.const 2) (i32.const 2) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 0)))
+                (then unreachable))
+            ;; The ring buffer is already polled alone
+            (i32.store (i32.const 68) (i32.const 12345))
+            (if (i32.ne (call $poll_multi (local.get $obj) (i32.const 64) (i32.const 2) (i32.const 2) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 0)) (i32.const -22))
+                (then unreachable))
+            (i32.store (i32.const 68) (global.get $rb_fd))
+            (call $spawn_exec_events)
+            (if (call $poll_multi (local.get $obj) (i32.const 64) (i32.const 2) (i32.const 2) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 1000))
+                (then unreachable))
+            (if (i32.lt_u (global.get $count) (i32.const 8)) (then unreachable)))
+        "#,
+    );
+    let args = ["test".to_string()];
+    let mut runner =
+        WasmBpfModuleRunner::new(&module_binary[..], &args[..], Config::default()).unwrap();
+    runner
+        .register_host_function("test", "spawn_exec_events", spawn_exec_events)
+        .unwrap();
+    let (_, wrapper) = runner.into_engine_and_entry_func().unwrap();
+    wrapper.run().unwrap();
+}
+
+#[test]
+fn test_poll_with_cpu_and_lost_count() {
+    let module_binary = build_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_attach_bpf_program" (func $attach (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_fd_by_name" (func $map_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_buffer_poll_with_cpu" (func $poll (param i64 i32 i32 i32 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_buffer_lost_count" (func $lost_count (param i64 i32) (result i64)))
+        (import "test" "spawn_exec_events" (func $spawn_exec_events))
+        (data (i32.const 16) "handle_exec\00")
+        (data (i32.const 32) "rb\00")
+        (global $count (mut i32) (i32.const 0))
+        (table (export "__indirect_function_table") 2 funcref)
+        (elem (i32.const 1) $on_sample)
+        (func $on_sample (param i32 i32 i32 i32) (result i32)
+            ;; Ring buffers don't tell the cpu
+            (if (i32.ne (local.get 1) (i32.const -1)) (then unreachable))
+            (global.set $count (i32.add (global.get $count) (i32.const 1)))
+            (i32.const 0))
+        (func (export "_start")
+            (local $obj i64)
+            (local $rb_fd i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (if (call $attach (local.get $obj) (i32.const 16) (i32.const 0)) (then unreachable))
+            (local.set $rb_fd (call $map_fd (local.get $obj) (i32.const 32)))
+            ;; The map isn't polled yet
+            (if (i64.ne (call $lost_count (local.get $obj) (local.get $rb_fd)) (i64.const -2))
+                (then unreachable))
+            (call $spawn_exec_events)
+            (if (call $poll (local.get $obj) (local.get $rb_fd) (i32.const 1) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 1000))
+                (then unreachable))
+            (if (i32.eqz (global.get $count)) (then unreachable))
+            (if (i64.ne (call $lost_count (local.get $obj) (local.get $rb_fd)) (i64.const 0))
+                (then unreachable)))
+        "#,
+    );
+    let args = ["test".to_string()];
+    let mut runner =
+        WasmBpfModuleRunner::new(&module_binary[..], &args[..], Config::default()).unwrap();
+    runner
+        .register_host_function("test", "spawn_exec_events", spawn_exec_events)
+        .unwrap();
+    let (_, wrapper) = runner.into_engine_and_entry_func().unwrap();
+    wrapper.run().unwrap();
+}
+
+#[test]
+fn test_poll_records_in_batches() {
+    let module_binary = build_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_attach_bpf_program" (func $attach (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_fd_by_name" (func $map_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_buffer_poll_batch" (func $poll_batch (param i64 i32 i32 i32 i32 i32 i32) (result i32)))
+        (import "test" "spawn_exec_events" (func $spawn_exec_events))
+        (data (i32.const 16) "handle_exec\00")
+        (data (i32.const 32) "rb\00")
+        (global $records (mut i32) (i32.const 0))
+        (global $batches (mut i32) (i32.const 0))
+        (table (export "__indirect_function_table") 2 funcref)
+        (elem (i32.const 1) $on_batch)
+        (func $on_batch (param $ctx i32) (param $data i32) (param $size i32) (param $count i32) (result i32)
+            (local $offset i32)
+            (local $seen i32)
+            ;; Walk the length-prefixed records
+            (block $done
+                (loop $next
+                    (br_if $done (i32.ge_u (local.get $offset) (local.get $size)))
+                    (local.set $offset
+                        (i32.and
+                            (i32.add
+                                (i32.add (local.get $offset) (i32.const 7))
+                                (i32.load (i32.add (local.get $data) (local.get $offset))))
+                            (i32.const -4)))
+                    (local.set $seen (i32.add (local.get $seen) (i32.const 1)))
+                    (br $next)))
+            (if (i32.ne (local.get $offset) (local.get $size)) (then unreachable))
+            (if (i32.ne (local.get $seen) (local.get $count)) (then unreachable))
+            (global.set $records (i32.add (global.get $records) (local.get $count)))
+            (global.set $batches (i32.add (global.get $batches) (i32.const 1)))
+            (i32.const 0))
+        (func (export "_start")
+            (local $obj i64)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (if (call $attach (local.get $obj) (i32.const 16) (i32.const 0)) (then unreachable))
+            (call $spawn_exec_events)
+            (if (call $poll_batch (local.get $obj) (call $map_fd (local.get $obj) (i32.const 32))
+                    (i32.const 1) (i32.const 0) (i32.const 1024) (i32.const 2048) (i32.const 1000))
+                (then unreachable))
+            (if (i32.lt_u (global.get $records) (i32.const 8)) (then unreachable))
+            ;; Several records are delivered with one call
+            (if (i32.ge_u (global.get $batches) (global.get $records)) (then unreachable)))
+        "#,
+    );
+    let args = ["test".to_string()];
+    let mut runner =
+        WasmBpfModuleRunner::new(&module_binary[..], &args[..], Config::default()).unwrap();
+    runner
+        .register_host_function("test", "spawn_exec_events", spawn_exec_events)
+        .unwrap();
+    let (_, wrapper) = runner.into_engine_and_entry_func().unwrap();
+    wrapper.run().unwrap();
+}
+
+#[test]
+fn test_detach_link() {
+    let module_binary = build_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_program_attach" (func $attach (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_link_detach" (func $detach (param i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_fd_by_name" (func $map_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_buffer_poll" (func $poll (param i64 i32 i32 i32 i32 i32 i32) (result i32)))
+        (import "test" "spawn_exec_events" (func $spawn_exec_events))
+        (data (i32.const 16) "handle_exec\00")
+        (data (i32.const 32) "rb\00")
+        (global $count (mut i32) (i32.const 0))
+        (table (export "__indirect_function_table") 2 funcref)
+        (elem (i32.const 1) $on_sample)
+        (func $on_sample (param i32 i32 i32) (result i32)
+            (global.set $count (i32.add (global.get $count) (i32.const 1)))
+            (i32.const 0))
+        (func (export "_start")
+            (local $obj i64)
+            (local $link i32)
+            (local $rb_fd i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (local.set $link (call $attach (local.get $obj) (i32.const 16) (i32.const 0)))
+            (if (i32.le_s (local.get $link) (i32.const 0)) (then unreachable))
+            (local.set $rb_fd (call $map_fd (local.get $obj) (i32.const 32)))
+            (call $spawn_exec_events)
+            (if (call $poll (local.get $obj) (local.get $rb_fd) (i32.const 1) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 1000))
+                (then unreachable))
+            (if (i32.eqz (global.get $count)) (then unreachable))
+            (if (call $detach (local.get $link)) (then unreachable))
+            ;; The link is gone
+            (if (i32.ne (call $detach (local.get $link)) (i32.const -9)) (then unreachable))
+            ;; Drain the records submitted before detaching
+            (if (call $poll (local.get $obj) (local.get $rb_fd) (i32.const 1) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 0))
+                (then unreachable))
+            (global.set $count (i32.const 0))
+            (call $spawn_exec_events)
+            (if (call $poll (local.get $obj) (local.get $rb_fd) (i32.const 1) (i32.const 0) (i32.const 1024) (i32.const 1024) (i32.const 200))
+                (then unreachable))
+            (if (global.get $count) (then unreachable)))
+        "#,
+    );
+    let args = ["test".to_string()];
+    let mut runner =
+        WasmBpfModuleRunner::new(&module_binary[..], &args[..], Config::default()).unwrap();
+    runner
+        .register_host_function("test", "spawn_exec_events", spawn_exec_events)
+        .unwrap();
+    let (_, wrapper) = runner.into_engine_and_entry_func().unwrap();
+    wrapper.run().unwrap();
+}
+
+#[test]
+fn test_attach_with_options() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_program_attach_opts" (func $attach (param i64 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_link_detach" (func $detach (param i32) (result i32)))
+        (data (i32.const 16) "handle_exec\00")
+        (data (i32.const 32) "handle_exit\00")
+        ;; sz = 32, cookie = 42
+        (data (i32.const 64) "\20\00\00\00\00\00\00\00\2a")
+        ;; sz = 32, flags = WASM_BPF_ATTACH_RETPROBE
+        (data (i32.const 128) "\20\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\01")
+        ;; sz = 40, attach_flags = BPF_F_ALLOW_OVERRIDE
+        (data (i32.const 192) "\28\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\01")
+        ;; sz = 48, cookie = 7, with unknown fields zeroed
+        (data (i32.const 256) "\30\00\00\00\00\00\00\00\07")
+        ;; sz = 48, with an unknown field set
+        (data (i32.const 384) "\30\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\01")
+        ;; sz = 16, an older version of the options, cookie = 1
+        (data (i32.const 320) "\10\00\00\00\00\00\00\00\01")
+        ;; sz = 0xfffffff0, beyond the guest memory
+        (data (i32.const 448) "\f0\ff\ff\ff")
+        (func (export "_start")
+            (local $obj i64)
+            (local $link i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (local.set $link (call $attach (local.get $obj) (i32.const 16) (i32.const 0) (i32.const 64)))
+            (if (i32.le_s (local.get $link) (i32.const 0)) (then unreachable))
+            (if (call $detach (local.get $link)) (then unreachable))
+            ;; Tracepoints don't have retprobes or attach flags
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 32) (i32.const 0) (i32.const 128)) (i32.const -22))
+                (then unreachable))
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 32) (i32.const 0) (i32.const 192)) (i32.const -22))
+                (then unreachable))
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 32) (i32.const 0) (i32.const 384)) (i32.const -22))
+                (then unreachable))
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 32) (i32.const 0) (i32.const 448)) (i32.const -14))
+                (then unreachable))
+            (local.set $link (call $attach (local.get $obj) (i32.const 32) (i32.const 0) (i32.const 256)))
+            (if (i32.le_s (local.get $link) (i32.const 0)) (then unreachable))
+            (if (call $detach (local.get $link)) (then unreachable))
+            (if (i32.le_s (call $attach (local.get $obj) (i32.const 16) (i32.const 0) (i32.const 320)) (i32.const 0))
+                (then unreachable))
+            ;; No options
+            (if (i32.le_s (call $attach (local.get $obj) (i32.const 32) (i32.const 0) (i32.const 0)) (i32.const 0))
+                (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_attach_xdp_with_replace() {
+    let object = build_trivial_bpf_object(&[("xdp", "xdp_old", 2), ("xdp", "xdp_new", 2)]);
+    run_wat_guest_with_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_program_attach_opts" (func $attach (param i64 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_program_fd_by_name" (func $prog_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_link_detach" (func $detach (param i32) (result i32)))
+        (data (i32.const 16) "xdp_old\00")
+        (data (i32.const 32) "xdp_new\00")
+        (data (i32.const 48) "lo\00")
+        ;; sz = 40, xdp_flags = XDP_FLAGS_SKB_MODE
+        (data (i32.const 64) "\28")
+        (data (i32.const 92) "\02")
+        ;; sz = 40, xdp_flags = XDP_FLAGS_SKB_MODE | XDP_FLAGS_REPLACE, replace_prog_fd set below
+        (data (i32.const 128) "\28")
+        (data (i32.const 156) "\12")
+        ;; sz = 40, xdp_flags = XDP_FLAGS_SKB_MODE | XDP_FLAGS_REPLACE without replace_prog_fd
+        (data (i32.const 192) "\28")
+        (data (i32.const 220) "\12")
+        (func (export "_start")
+            (local $obj i64)
+            (local $old i32)
+            (local $new i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (local.set $old (call $attach (local.get $obj) (i32.const 16) (i32.const 48) (i32.const 64)))
+            (if (i32.le_s (local.get $old) (i32.const 0)) (then unreachable))
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 32) (i32.const 48) (i32.const 192)) (i32.const -22))
+                (then unreachable))
+            ;; Replacing a program which isn't attached fails
+            (i32.store (i32.const 164) (call $prog_fd (local.get $obj) (i32.const 32)))
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 32) (i32.const 48) (i32.const 128)) (i32.const -17))
+                (then unreachable))
+            (i32.store (i32.const 164) (call $prog_fd (local.get $obj) (i32.const 16)))
+            (local.set $new (call $attach (local.get $obj) (i32.const 32) (i32.const 48) (i32.const 128)))
+            (if (i32.le_s (local.get $new) (i32.const 0)) (then unreachable))
+            (if (call $detach (local.get $new)) (then unreachable))
+            ;; The replaced program is no longer attached, so nothing is detached
+            (if (call $detach (local.get $old)) (then unreachable)))
+        "#,
+        &object,
+    )
+    .unwrap();
+}
+
+/// Create a temporary cgroup in the cgroup v2 hierarchy, named with `name` and the pid
+fn create_temp_cgroup(name: &str) -> PathBuf {
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap();
+    let root = mounts
+        .lines()
+        .map(|v| v.split(' ').collect::<Vec<_>>())
+        .find(|v| v.len() > 2 && v[2] == "cgroup2")
+        .map(|v| v[1].to_string())
+        .expect("cgroup v2 is not mounted");
+    let path = PathBuf::from(root).join(format!("{}-{}", name, std::process::id()));
+    std::fs::create_dir(&path).unwrap();
+    path
+}
+
+#[test]
+fn test_attach_cgroup_with_replace() {
+    let object = build_trivial_bpf_object(&[
+        ("cgroup_skb/ingress", "cg_old", 1),
+        ("cgroup_skb/ingress", "cg_new", 1),
+    ]);
+    let cgroup = create_temp_cgroup("wasm-bpf-test-cgroup");
+    let module_binary = build_wat_guest_with_object(
+        &format!(
+            r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_program_attach_opts" (func $attach (param i64 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_program_fd_by_name" (func $prog_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_link_detach" (func $detach (param i32) (result i32)))
+        (import "test" "prog_count" (func $count (result i32)))
+        (data (i32.const 16) "cg_old\00")
+        (data (i32.const 32) "cg_new\00")
+        (data (i32.const 256) "{}\00")
+        ;; sz = 40, attach_flags = BPF_F_ALLOW_MULTI
+        (data (i32.const 64) "\28")
+        (data (i32.const 96) "\02")
+        ;; sz = 40, attach_flags = BPF_F_ALLOW_MULTI | BPF_F_REPLACE, replace_prog_fd set below
+        (data (i32.const 128) "\28")
+        (data (i32.const 160) "\06")
+        (func (export "_start")
+            (local $obj i64)
+            (local $old i32)
+            (local $new i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (local.set $old (call $attach (local.get $obj) (i32.const 16) (i32.const 256) (i32.const 64)))
+            (if (i32.le_s (local.get $old) (i32.const 0)) (then unreachable))
+            (if (i32.ne (call $count) (i32.const 1)) (then unreachable))
+            (i32.store (i32.const 164) (call $prog_fd (local.get $obj) (i32.const 16)))
+            (local.set $new (call $attach (local.get $obj) (i32.const 32) (i32.const 256) (i32.const 128)))
+            (if (i32.le_s (local.get $new) (i32.const 0)) (then unreachable))
+            (if (i32.ne (call $count) (i32.const 1)) (then unreachable))
+            ;; The replaced program is no longer attached, so it can't be replaced again
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 16) (i32.const 256) (i32.const 128)) (i32.const -2))
+                (then unreachable))
+            (if (call $detach (local.get $old)) (then unreachable))
+            (if (i32.ne (call $count) (i32.const 1)) (then unreachable))
+            (if (call $detach (local.get $new)) (then unreachable))
+            (if (call $count) (then unreachable))
+            ;; Without attach flags, the program is attached through a link
+            (local.set $old (call $attach (local.get $obj) (i32.const 16) (i32.const 256) (i32.const 0)))
+            (if (i32.le_s (local.get $old) (i32.const 0)) (then unreachable))
+            (if (i32.ne (call $count) (i32.const 1)) (then unreachable))
+            (if (call $detach (local.get $old)) (then unreachable))
+            (if (call $count) (then unreachable)))
+        "#,
+            cgroup.display()
+        ),
+        &object,
+    );
+    let cgroup_path = cgroup.clone();
+    let count_attached = move |_: CallerType| {
+        let cgroup = File::open(&cgroup_path).unwrap();
+        let mut prog_ids = [0u32; 4];
+        let mut prog_cnt = prog_ids.len() as u32;
+        // SAFETY: the outputs live through the call
+        let err = unsafe {
+            bpf_prog_query(
+                cgroup.as_raw_fd(),
+                BPF_CGROUP_INET_INGRESS,
+                0,
+                std::ptr::null_mut(),
+                prog_ids.as_mut_ptr(),
+                &mut prog_cnt,
+            )
+        };
+        assert_eq!(err, 0);
+        prog_cnt as i32
+    };
+    let args = ["test".to_string()];
+    let mut runner =
+        WasmBpfModuleRunner::new(&module_binary[..], &args[..], Config::default()).unwrap();
+    runner
+        .register_host_function("test", "prog_count", count_attached)
+        .unwrap();
+    let result = runner.into_engine_and_entry_func().unwrap().1.run();
+    std::fs::remove_dir(&cgroup).unwrap();
+    result.unwrap();
+}
+
+/// A function for resolving in the test binary
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn wasm_bpf_test_uprobe_target(v: u64) -> u64 {
+    v.wrapping_mul(0x9e3779b97f4a7c15).rotate_left(17) ^ 0x5bd1e995
+}
+
+#[test]
+fn test_attach_uprobe_with_overflowing_offset() {
+    let object = build_trivial_bpf_object(&[("uprobe", "uprobe_target", 0)]);
+    let target = format!(
+        "{}:wasm_bpf_test_uprobe_target+0x10",
+        std::env::current_exe().unwrap().display()
+    );
+    run_wat_guest_with_object(
+        &format!(
+            r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_program_attach_opts" (func $attach (param i64 i32 i32 i32) (result i32)))
+        (data (i32.const 16) "uprobe_target\00")
+        ;; sz = 24, offset = 0xfffffffffffffff8
+        (data (i32.const 64) "\18\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\f8\ff\ff\ff\ff\ff\ff\ff")
+        (data (i32.const 128) "{}\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 16) (i32.const 128) (i32.const 64)) (i32.const -22))
+                (then unreachable)))
+        "#,
+            target
+        ),
+        &object,
+    )
+    .unwrap();
+}
+
+/// A function with the USDT probe `wasm_bpf_test:probe`, described by a `.note.stapsdt`
+/// note in the test binary like the ones of `sys/sdt.h`, without arguments
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn wasm_bpf_test_usdt_target() {
+    // SAFETY: the probe is only a nop, and the note isn't loaded
+    unsafe {
+        std::arch::asm!(
+            "990: nop",
+            ".pushsection .note.stapsdt, \"\", \"note\"",
+            ".balign 4",
+            ".4byte 992f-991f, 994f-993f, 3",
+            "991: .asciz \"stapsdt\"",
+            "992: .balign 4",
+            // The address of the probe, the base address and the semaphore
+            "993: .8byte 990b",
+            ".8byte 0",
+            ".8byte 0",
+            ".asciz \"wasm_bpf_test\"",
+            ".asciz \"probe\"",
+            ".asciz \"\"",
+            "994: .balign 4",
+            ".popsection",
+            options(nostack)
+        );
+    }
+}
+
+#[test]
+fn test_attach_usdt() {
+    let object = build_usdt_bpf_object();
+    let target = format!(
+        "{}:wasm_bpf_test:probe",
+        std::env::current_exe().unwrap().display()
+    );
+    run_wat_guest_with_object(
+        &format!(
+            r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_program_attach" (func $attach (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_program_attach_opts" (func $attach_opts (param i64 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_link_detach" (func $detach (param i32) (result i32)))
+        (data (i32.const 16) "usdt_probe\00")
+        (data (i32.const 32) "/proc/self/exe:wasm_bpf_test:no_such_probe\00")
+        ;; sz = 28, flags = WASM_BPF_ATTACH_RETPROBE
+        (data (i32.const 96) "\1c\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\01\00\00\00")
+        (data (i32.const 128) "{}\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local $link i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (local.set $link (call $attach (local.get $obj) (i32.const 16) (i32.const 128)))
+            (if (i32.le_s (local.get $link) (i32.const 0)) (then unreachable))
+            (if (call $detach (local.get $link)) (then unreachable))
+            (if (i32.ne (call $detach (local.get $link)) (i32.const -9)) (then unreachable))
+            ;; USDTs have no return probes
+            (if (i32.ne (call $attach_opts (local.get $obj) (i32.const 16) (i32.const 128) (i32.const 96)) (i32.const -22))
+                (then unreachable))
+            ;; No such probe in the binary
+            (if (i32.ge_s (call $attach (local.get $obj) (i32.const 16) (i32.const 32)) (i32.const 0))
+                (then unreachable)))
+        "#,
+            target
+        ),
+        &object,
+    )
+    .unwrap();
+    wasm_bpf_test_usdt_target();
+}
+
+#[test]
+fn test_parse_uprobe_target() {
+    assert_eq!(
+        UprobeTarget::parse("/usr/lib/libssl.so:SSL_write"),
+        Some(UprobeTarget {
+            binary: "/usr/lib/libssl.so",
+            symbol: Some("SSL_write"),
+            offset: 0
+        })
+    );
+    assert_eq!(
+        UprobeTarget::parse("./target:uprobe_add+0x10"),
+        Some(UprobeTarget {
+            binary: "./target",
+            symbol: Some("uprobe_add"),
+            offset: 16
+        })
+    );
+    assert_eq!(
+        UprobeTarget::parse("/bin/bash"),
+        Some(UprobeTarget {
+            binary: "/bin/bash",
+            symbol: None,
+            offset: 0
+        })
+    );
+    assert_eq!(UprobeTarget::parse(":SSL_write"), None);
+    assert_eq!(UprobeTarget::parse("/bin/bash:"), None);
+    assert_eq!(UprobeTarget::parse("/bin/bash:main+x"), None);
+}
+
+#[test]
+fn test_parse_usdt_target() {
+    assert_eq!(
+        UsdtTarget::parse("/usr/bin/python3:python:function__entry"),
+        Some(UsdtTarget {
+            binary: "/usr/bin/python3",
+            provider: "python",
+            name: "function__entry"
+        })
+    );
+    // Only the last two parts are the provider and the name
+    assert_eq!(
+        UsdtTarget::parse("./a:b:libc:setjmp"),
+        Some(UsdtTarget {
+            binary: "./a:b",
+            provider: "libc",
+            name: "setjmp"
+        })
+    );
+    assert_eq!(UsdtTarget::parse("/usr/bin/python3:function__entry"), None);
+    assert_eq!(UsdtTarget::parse("/usr/bin/python3::function__entry"), None);
+    assert_eq!(UsdtTarget::parse(":python:function__entry"), None);
+}
+
+#[test]
+fn test_attach_tc_and_destroy_created_qdisc() {
+    let object = build_trivial_bpf_object(&[("tc", "tc_ingress", 0), ("tc", "tc_egress", 0)]);
+    let module_binary = build_wat_guest_with_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_program_attach" (func $attach (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_link_detach" (func $detach (param i32) (result i32)))
+        (import "test" "clsact_exists" (func $clsact_exists (result i32)))
+        (data (i32.const 16) "tc_ingress\00")
+        (data (i32.const 32) "tc_egress\00")
+        (data (i32.const 48) "lo:ingress\00")
+        (data (i32.const 64) "lo:egress\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local $ingress i32)
+            (local $egress i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (if (call $clsact_exists) (then unreachable))
+            (local.set $ingress (call $attach (local.get $obj) (i32.const 16) (i32.const 48)))
+            (if (i32.le_s (local.get $ingress) (i32.const 0)) (then unreachable))
+            (local.set $egress (call $attach (local.get $obj) (i32.const 32) (i32.const 64)))
+            (if (i32.le_s (local.get $egress) (i32.const 0)) (then unreachable))
+            (if (i32.eqz (call $clsact_exists)) (then unreachable))
+            ;; The qdisc is kept until the last program on it is detached
+            (if (call $detach (local.get $ingress)) (then unreachable))
+            (if (i32.eqz (call $clsact_exists)) (then unreachable))
+            (if (call $detach (local.get $egress)) (then unreachable))
+            (if (call $clsact_exists) (then unreachable)))
+        "#,
+        &object,
+    );
+    let clsact_exists = |_: CallerType| {
+        let output = std::process::Command::new("tc")
+            .args(["qdisc", "show", "dev", "lo"])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).contains("clsact") as i32
+    };
+    let args = ["test".to_string()];
+    let mut runner =
+        WasmBpfModuleRunner::new(&module_binary[..], &args[..], Config::default()).unwrap();
+    runner
+        .register_host_function("test", "clsact_exists", clsact_exists)
+        .unwrap();
+    runner
+        .into_engine_and_entry_func()
+        .unwrap()
+        .1
+        .run()
+        .unwrap();
+}
+
+#[test]
+fn test_parse_tc_target() {
+    assert_eq!(
+        TcTarget::parse("eth0:ingress"),
+        Some(TcTarget {
+            interface: "eth0",
+            ingress: true,
+            priority: 0,
+            handle: 0
+        })
+    );
+    assert_eq!(
+        TcTarget::parse("veth0:egress:1:0x10"),
+        Some(TcTarget {
+            interface: "veth0",
+            ingress: false,
+            priority: 1,
+            handle: 16
+        })
+    );
+    assert_eq!(TcTarget::parse("eth0"), None);
+    assert_eq!(TcTarget::parse("eth0:both"), None);
+    assert_eq!(TcTarget::parse(":ingress"), None);
+    assert_eq!(TcTarget::parse("eth0:ingress:high"), None);
+    assert_eq!(TcTarget::parse("eth0:ingress:1:1:1"), None);
+}
+
+#[test]
+fn test_resolve_uprobe_symbol_offset() {
+    let exe = std::env::current_exe().unwrap();
+    let offset = resolve_symbol_offset(&exe, "wasm_bpf_test_uprobe_target").unwrap() as usize;
+    // The code in the file at the offset is the one being run
+    let mut file_content = vec![];
+    File::open(&exe)
+        .unwrap()
+        .read_to_end(&mut file_content)
+        .unwrap();
+    let code = wasm_bpf_test_uprobe_target as *const u8;
+    // SAFETY: the function has more than 16 bytes of code
+    let code = unsafe { std::slice::from_raw_parts(code, 16) };
+    assert_eq!(&file_content[offset..offset + 16], code);
+    assert_eq!(
+        resolve_symbol_offset(&exe, "wasm_bpf_test_no_such_function"),
+        Err(-2)
+    );
+    assert!(resolve_symbol_offset(&get_test_file_path("no_such_binary"), "main").is_err());
+}
+
+/// Load a program of raw instructions, and return its fd
+fn load_program(prog_type: bpf_prog_type, opts: &bpf_prog_load_opts, insns: &[[u8; 8]]) -> i32 {
+    // SAFETY: bpf_insn is 8 bytes
+    let insns: Vec<bpf_insn> = insns
+        .iter()
+        .map(|v| unsafe { std::mem::transmute(*v) })
+        .collect();
+    // SAFETY: the instructions, the license and the options live through the call
+    let prog_fd = unsafe {
+        bpf_prog_load(
+            prog_type,
+            std::ptr::null(),
+            c"GPL".as_ptr(),
+            insns.as_ptr(),
+            insns.len() as _,
+            opts,
+        )
+    };
+    assert!(prog_fd >= 0);
+    prog_fd
+}
+
+/// Load a program which only returns `retval`, and return its fd
+fn load_trivial_program(
+    prog_type: bpf_prog_type,
+    expected_attach_type: bpf_attach_type,
+    retval: i32,
+) -> i32 {
+    let [a, b, c, d] = retval.to_le_bytes();
+    let opts = bpf_prog_load_opts {
+        sz: std::mem::size_of::<bpf_prog_load_opts>() as _,
+        expected_attach_type,
+        ..Default::default()
+    };
+    // `r0 = retval; exit`
+    load_program(
+        prog_type,
+        &opts,
+        &[[0xb7, 0, 0, 0, a, b, c, d], [0x95, 0, 0, 0, 0, 0, 0, 0]],
+    )
+}
+
+const SHT_PROGBITS: u32 = 1;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+/// A section of a bpf object built by `build_elf`
+struct ElfSection {
+    name: &'static str,
+    sh_type: u32,
+    flags: u64,
+    data: Vec<u8>,
+}
+
+/// A global symbol of a bpf object built by `build_elf`
+struct ElfSymbol {
+    name: &'static str,
+    /// The index of the section, where 1 is the first section passed
+    section: u16,
+    /// `STT_FUNC` or `STT_OBJECT`
+    sym_type: u8,
+    /// The offset in the section
+    value: u64,
+    size: u64,
+}
+
+/// Build a relocatable ELF file for bpf with the sections and symbols.
+/// `.strtab` and `.symtab` are appended
+fn build_elf(mut sections: Vec<ElfSection>, symbols: &[ElfSymbol]) -> Vec<u8> {
+    const SHT_SYMTAB: u32 = 2;
+    const SHT_STRTAB: u32 = 3;
+    let mut strtab = vec![0u8];
+    let mut add_str = |name: &str| {
+        let offset = strtab.len() as u32;
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+        offset
+    };
+    let mut symtab = vec![0u8; 24];
+    for symbol in symbols {
+        symtab.extend_from_slice(&add_str(symbol.name).to_le_bytes());
+        // STB_GLOBAL
+        symtab.push((1 << 4) | symbol.sym_type);
+        symtab.push(0);
+        symtab.extend_from_slice(&symbol.section.to_le_bytes());
+        symtab.extend_from_slice(&symbol.value.to_le_bytes());
+        symtab.extend_from_slice(&symbol.size.to_le_bytes());
+    }
+    let section_names: Vec<u32> = sections
+        .iter()
+        .map(|v| v.name)
+        .chain([".strtab", ".symtab"])
+        .map(&mut add_str)
+        .collect();
+    let strtab_index = sections.len() as u32 + 1;
+    sections.push(ElfSection {
+        name: ".strtab",
+        sh_type: SHT_STRTAB,
+        flags: 0,
+        data: strtab,
+    });
+    sections.push(ElfSection {
+        name: ".symtab",
+        sh_type: SHT_SYMTAB,
+        flags: 0,
+        data: symtab,
+    });
+    let mut body = vec![];
+    let mut headers = vec![0u8; 64];
+    for (i, section) in sections.iter().enumerate() {
+        body.resize(body.len().next_multiple_of(8), 0);
+        let offset = 64 + body.len() as u64;
+        body.extend_from_slice(&section.data);
+        let (link, info, entsize) = if section.sh_type == SHT_SYMTAB {
+            // All symbols but the null one are global
+            (strtab_index, 1u32, 24u64)
+        } else {
+            (0, 0, 0)
+        };
+        headers.extend_from_slice(&section_names[i].to_le_bytes());
+        headers.extend_from_slice(&section.sh_type.to_le_bytes());
+        headers.extend_from_slice(&section.flags.to_le_bytes());
+        headers.extend_from_slice(&0u64.to_le_bytes());
+        headers.extend_from_slice(&offset.to_le_bytes());
+        headers.extend_from_slice(&(section.data.len() as u64).to_le_bytes());
+        headers.extend_from_slice(&link.to_le_bytes());
+        headers.extend_from_slice(&info.to_le_bytes());
+        headers.extend_from_slice(&8u64.to_le_bytes());
+        headers.extend_from_slice(&entsize.to_le_bytes());
+    }
+    body.resize(body.len().next_multiple_of(8), 0);
+    let section_headers_offset = 64 + body.len() as u64;
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1];
+    elf.resize(16, 0);
+    // ET_REL, EM_BPF
+    elf.extend_from_slice(&1u16.to_le_bytes());
+    elf.extend_from_slice(&247u16.to_le_bytes());
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&0u64.to_le_bytes());
+    elf.extend_from_slice(&0u64.to_le_bytes());
+    elf.extend_from_slice(&section_headers_offset.to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    for v in [64u16, 0, 0, 64, sections.len() as u16 + 1] {
+        elf.extend_from_slice(&v.to_le_bytes());
+    }
+    elf.extend_from_slice(&(strtab_index as u16).to_le_bytes());
+    elf.extend_from_slice(&body);
+    elf.extend_from_slice(&headers);
+    elf
+}
+
+/// Build the sections and symbols of programs which only return a value, given as
+/// (section name, program name, return value), with the license section at the end
+fn trivial_program_sections(
+    programs: &[(&'static str, &'static str, i32)],
+) -> (Vec<ElfSection>, Vec<ElfSymbol>) {
+    let mut sections = vec![];
+    let mut symbols = vec![];
+    for (i, (section, name, retval)) in programs.iter().enumerate() {
+        // `r0 = retval; exit`
+        let mut data = vec![0xb7, 0, 0, 0];
+        data.extend_from_slice(&retval.to_le_bytes());
+        data.extend_from_slice(&[0x95, 0, 0, 0, 0, 0, 0, 0]);
+        symbols.push(ElfSymbol {
+            name,
+            section: i as u16 + 1,
+            sym_type: STT_FUNC,
+            value: 0,
+            size: data.len() as u64,
+        });
+        sections.push(ElfSection {
+            name: section,
+            sh_type: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            data,
+        });
+    }
+    sections.push(ElfSection {
+        name: "license",
+        sh_type: SHT_PROGBITS,
+        flags: SHF_ALLOC | SHF_WRITE,
+        data: b"GPL\0".to_vec(),
+    });
+    (sections, symbols)
+}
+
+/// Build a bpf object of programs which only return a value, given as
+/// (section name, program name, return value), so that attaching programs of
+/// types missing in the test objects can be tested
+fn build_trivial_bpf_object(programs: &[(&'static str, &'static str, i32)]) -> Vec<u8> {
+    let (sections, symbols) = trivial_program_sections(programs);
+    build_elf(sections, &symbols)
+}
+
+/// Build a bpf object with a trivial USDT program `usdt_probe`, and the maps that libbpf
+/// requires for attaching USDTs, `__bpf_usdt_specs` and `__bpf_usdt_ip_to_spec_id`,
+/// defined in `.maps` by BTF
+fn build_usdt_bpf_object() -> Vec<u8> {
+    // (name, map type, key size, value size), where the value of `__bpf_usdt_specs` is
+    // `struct __bpf_usdt_spec` of usdt.bpf.h
+    let maps = [
+        ("__bpf_usdt_specs", BPF_MAP_TYPE_ARRAY, 4, 208),
+        ("__bpf_usdt_ip_to_spec_id", BPF_MAP_TYPE_HASH, 8, 4),
+    ];
+    // Each map is a struct of 4 pointers, like `__uint(type, BPF_MAP_TYPE_ARRAY)`
+    const MAP_DEF_SIZE: u32 = 32;
+    let (mut sections, mut symbols) = trivial_program_sections(&[("usdt", "usdt_probe", 0)]);
+    let maps_section = sections.len() as u16 + 1;
+    // SAFETY: the BTF is only used here, and freed after its data is copied
+    let btf_data = unsafe {
+        let btf = btf__new_empty();
+        let name = |v: &str| CString::new(v).unwrap();
+        let int = btf__add_int(btf, name("int").as_ptr(), 4, BTF_INT_SIGNED as i32);
+        // `int (*name)[value]`, the way BTF carries the numbers of map definitions
+        let uint = |value: u32| btf__add_ptr(btf, btf__add_array(btf, int, int, value));
+        let mut vars = vec![];
+        for (i, (map_name, map_type, key_size, value_size)) in maps.iter().enumerate() {
+            let fields = [
+                ("type", uint(*map_type)),
+                ("max_entries", uint(256)),
+                ("key_size", uint(*key_size)),
+                ("value_size", uint(*value_size)),
+            ];
+            // Fields are added right after the struct
+            let def = btf__add_struct(btf, std::ptr::null(), MAP_DEF_SIZE);
+            for (j, (field, field_type)) in fields.iter().enumerate() {
+                btf__add_field(btf, name(field).as_ptr(), *field_type, j as u32 * 64, 0);
+            }
+            // BTF_VAR_GLOBAL_ALLOCATED
+            vars.push((btf__add_var(btf, name(map_name).as_ptr(), 1, def), i as u32));
+        }
+        btf__add_datasec(
+            btf,
+            name(".maps").as_ptr(),
+            MAP_DEF_SIZE * maps.len() as u32,
+        );
+        for (var, i) in vars {
+            btf__add_datasec_var_info(btf, var, i * MAP_DEF_SIZE, MAP_DEF_SIZE);
+        }
+        let mut size = 0;
+        let data = btf__raw_data(btf, &mut size);
+        let data = std::slice::from_raw_parts(data as *const u8, size as usize).to_vec();
+        btf__free(btf);
+        data
+    };
+    for (i, (map_name, ..)) in maps.iter().enumerate() {
+        symbols.push(ElfSymbol {
+            name: map_name,
+            section: maps_section,
+            sym_type: STT_OBJECT,
+            value: i as u64 * MAP_DEF_SIZE as u64,
+            size: MAP_DEF_SIZE as u64,
+        });
+    }
+    sections.push(ElfSection {
+        name: ".maps",
+        sh_type: SHT_PROGBITS,
+        flags: SHF_ALLOC | SHF_WRITE,
+        data: vec![0; MAP_DEF_SIZE as usize * maps.len()],
+    });
+    sections.push(ElfSection {
+        name: ".BTF",
+        sh_type: SHT_PROGBITS,
+        flags: 0,
+        data: btf_data,
+    });
+    build_elf(sections, &symbols)
+}
+
+#[test]
+fn test_packet_socket_with_socket_filter() {
+    // Pass every packet
+    let prog_fd = load_trivial_program(BPF_PROG_TYPE_SOCKET_FILTER, 0, -1);
+    let socket = PacketSocket::open(interface_index("lo").unwrap(), prog_fd).unwrap();
+    let payload = b"wasm-bpf socket filter test";
+    let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp.send_to(payload, udp.local_addr().unwrap()).unwrap();
+    let mut buf = [0u8; 2048];
+    // Other packets may go through lo
+    let found = (0..100).any(|_| match socket.recv(&mut buf, 100).unwrap() {
+        0 => false,
+        size => buf[..size]
+            .windows(payload.len())
+            .any(|v| v == &payload[..]),
+    });
+    assert!(found);
+    // The size of the truncated packet is returned
+    udp.send_to(payload, udp.local_addr().unwrap()).unwrap();
+    let mut small_buf = [0u8; 8];
+    assert!(socket.recv(&mut small_buf, 1000).unwrap() > small_buf.len());
+    drop(socket);
+    // SAFETY: the fd is owned here
+    unsafe { libc::close(prog_fd) };
+}
+
+#[test]
+fn test_socket_filter_host_functions() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_socket_filter_open" (func $open (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_socket_filter_read" (func $read (param i32 i32 i32 i32) (result i32)))
+        (data (i32.const 16) "handle_exec\00")
+        (data (i32.const 32) "no_such_prog\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            ;; Not a socket filter
+            (if (i32.ne (call $open (local.get $obj) (i32.const 16) (i32.const 0)) (i32.const -22))
+                (then unreachable))
+            (if (i32.ne (call $open (local.get $obj) (i32.const 32) (i32.const 0)) (i32.const -2))
+                (then unreachable))
+            (if (i32.ne (call $read (i32.const 1) (i32.const 64) (i32.const 64) (i32.const 0)) (i32.const -9))
+                (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_attach_program_to_sockmap() {
+    let count_attached = |map_fd| {
+        let mut prog_ids = [0u32; 4];
+        let mut prog_cnt = prog_ids.len() as u32;
+        // SAFETY: the outputs live through the call
+        let err = unsafe {
+            bpf_prog_query(
+                map_fd,
+                BPF_SK_MSG_VERDICT,
+                0,
+                std::ptr::null_mut(),
+                prog_ids.as_mut_ptr(),
+                &mut prog_cnt,
+            )
+        };
+        assert_eq!(err, 0);
+        prog_cnt
+    };
+    // SAFETY: no pointers are passed
+    let map_fd = unsafe {
+        bpf_map_create(
+            BPF_MAP_TYPE_SOCKMAP,
+            std::ptr::null(),
+            4,
+            4,
+            1,
+            std::ptr::null(),
+        )
+    };
+    assert!(map_fd >= 0);
+    // SK_PASS
+    let prog_fd = load_trivial_program(BPF_PROG_TYPE_SK_MSG, BPF_SK_MSG_VERDICT, 1);
+    let attachment = attach_to_map(prog_fd, map_fd, BPF_SK_MSG_VERDICT).unwrap();
+    assert_eq!(count_attached(map_fd), 1);
+    drop(attachment);
+    assert_eq!(count_attached(map_fd), 0);
+    assert_eq!(
+        attach_to_map(prog_fd, -1, BPF_SK_MSG_VERDICT).err(),
+        Some(-libc::EBADF)
+    );
+    // SAFETY: the fds are owned here
+    unsafe {
+        libc::close(prog_fd);
+        libc::close(map_fd);
+    }
+}
+
+#[test]
+fn test_parse_cpu_list() {
+    assert_eq!(parse_cpu_list("0\n"), Some(vec![0]));
+    assert_eq!(parse_cpu_list("0-3,5,7-8"), Some(vec![0, 1, 2, 3, 5, 7, 8]));
+    assert_eq!(parse_cpu_list(""), None);
+    assert_eq!(parse_cpu_list("3-1"), None);
+    assert_eq!(parse_cpu_list("0-"), None);
+}
+
+#[test]
+fn test_attach_perf_event() {
+    use libbpf_rs::libbpf_sys::{PERF_COUNT_SW_CPU_CLOCK, PERF_TYPE_SOFTWARE};
+    // The software cpu-clock event is available without PMU hardware
+    let perf_event = open_perf_event(
+        PERF_TYPE_SOFTWARE,
+        PERF_COUNT_SW_CPU_CLOCK as u64,
+        99,
+        true,
+        0,
+    )
+    .unwrap();
+    drop(perf_event);
+    assert!(open_perf_event(
+        PERF_TYPE_SOFTWARE,
+        PERF_COUNT_SW_CPU_CLOCK as u64,
+        1,
+        false,
+        -2
+    )
+    .is_err());
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_program_attach_perf_event"
+            (func $attach (param i64 i32 i32 i64 i64 i32) (result i32)))
+        (data (i32.const 16) "handle_exec\00")
+        (data (i32.const 32) "no_such_prog\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            ;; Not a perf_event program
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 16) (i32.const 1) (i64.const 0) (i64.const 99) (i32.const 1))
+                    (i32.const -22))
+                (then unreachable))
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 32) (i32.const 1) (i64.const 0) (i64.const 99) (i32.const 1))
+                    (i32.const -2))
+                (then unreachable))
+            ;; Unknown flags
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 16) (i32.const 1) (i64.const 0) (i64.const 99) (i32.const 2))
+                    (i32.const -22))
+                (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_parse_kallsyms() {
+    let symbols = SymbolTable::from_kallsyms(
+        "ffffffff81000000 T _stext\n\
+         ffffffff81000100 t do_one_initcall\n\
+         ffffffff82000000 D jiffies\n\
+         ffffffffc0000000 t nf_hook [nf_tables]\n",
+    );
+    assert_eq!(symbols.lookup(0xffffffff81000000), Some(("_stext", 0)));
+    assert_eq!(
+        symbols.lookup(0xffffffff81000123),
+        Some(("do_one_initcall", 0x23))
+    );
+    // Data symbols are skipped
+    assert_eq!(
+        symbols.lookup(0xffffffff82000000),
+        Some(("do_one_initcall", 0xffff00))
+    );
+    assert_eq!(symbols.lookup(0xffffffffc0000010), Some(("nf_hook", 0x10)));
+    assert_eq!(symbols.lookup(0x1000), None);
+}
+
+#[test]
+fn test_parse_proc_maps() {
+    let mappings = parse_proc_maps(
+        "55d0c5a4f000-55d0c5a51000 r-xp 00002000 08:01 1234                       /usr/bin/cat\n\
+         7ffd4c5e3000-7ffd4c604000 rw-p 00000000 00:00 0                          [stack]\n\
+         7f1e2a400000-7f1e2a428000 r--p 00000000 00:00 0 \n",
+    );
+    assert_eq!(
+        mappings,
+        vec![Mapping {
+            start: 0x55d0c5a4f000,
+            end: 0x55d0c5a51000,
+            offset: 0x2000,
+            path: "/usr/bin/cat".to_string(),
+        }]
+    );
+}
+
+#[test]
+fn test_resolve_user_symbols() {
+    let mut symbolizer = Symbolizer::default();
+    let pid = std::process::id() as i32;
+    let address = wasm_bpf_test_uprobe_target as *const u8 as u64;
+    assert_eq!(
+        symbolizer.resolve_user(pid, address + 3),
+        Ok(("wasm_bpf_test_uprobe_target", 3))
+    );
+    // Unmapped addresses
+    assert_eq!(symbolizer.resolve_user(pid, 0x10), Err(-2));
+    assert!(symbolizer.resolve_user(-2, address).is_err());
+}
+
+#[test]
+fn test_read_task_iterator() {
+    // SAFETY: the name lives through the call
+    let btf_id = unsafe { libbpf_find_vmlinux_btf_id(c"task".as_ptr(), BPF_TRACE_ITER) };
+    assert!(btf_id > 0);
+    let opts = bpf_prog_load_opts {
+        sz: std::mem::size_of::<bpf_prog_load_opts>() as _,
+        expected_attach_type: BPF_TRACE_ITER,
+        attach_btf_id: btf_id as u32,
+        ..Default::default()
+    };
+    // `bpf_seq_write(ctx->meta->seq, "ok\n", 3); return 0;`
+    let prog_fd = load_program(
+        BPF_PROG_TYPE_TRACING,
+        &opts,
+        &[
+            [0x79, 0x16, 0, 0, 0, 0, 0, 0],
+            [0x79, 0x61, 0, 0, 0, 0, 0, 0],
+            [0x62, 0x0a, 0xf8, 0xff, b'o', b'k', b'\n', 0],
+            [0xbf, 0xa2, 0, 0, 0, 0, 0, 0],
+            [0x07, 0x02, 0, 0, 0xf8, 0xff, 0xff, 0xff],
+            [0xb7, 0x03, 0, 0, 3, 0, 0, 0],
+            [0x85, 0, 0, 0, 127, 0, 0, 0],
+            [0xb7, 0, 0, 0, 0, 0, 0, 0],
+            [0x95, 0, 0, 0, 0, 0, 0, 0],
+        ],
+    );
+    // SAFETY: no options are passed
+    let link_fd = unsafe { bpf_link_create(prog_fd, 0, BPF_TRACE_ITER, std::ptr::null()) };
+    assert!(link_fd >= 0);
+    // Each iterator walks the tasks once
+    for _ in 0..2 {
+        let mut iter = create_iter(link_fd).unwrap();
+        let mut output = String::new();
+        iter.read_to_string(&mut output).unwrap();
+        assert!(output.lines().count() > 1);
+        assert!(output.lines().all(|v| v == "ok"));
+    }
+    assert!(create_iter(prog_fd).is_err());
+    // SAFETY: the fds are owned here
+    unsafe {
+        libc::close(link_fd);
+        libc::close(prog_fd);
+    }
+}
+
+#[test]
+fn test_iterator_host_functions() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_program_attach" (func $attach (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_iter_create" (func $create (param i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_iter_read" (func $read (param i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_iter_close" (func $close (param i32) (result i32)))
+        (data (i32.const 16) "handle_exec\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local $link i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (local.set $link (call $attach (local.get $obj) (i32.const 16) (i32.const 0)))
+            (if (i32.le_s (local.get $link) (i32.const 0)) (then unreachable))
+            ;; Not a link of an iterator program
+            (if (i32.ne (call $create (local.get $link)) (i32.const -22))
+                (then unreachable))
+            (if (i32.ne (call $create (i32.const 12345)) (i32.const -9))
+                (then unreachable))
+            (if (i32.ne (call $read (i32.const 12345) (i32.const 64) (i32.const 64)) (i32.const -9))
+                (then unreachable))
+            (if (i32.ne (call $close (i32.const 12345)) (i32.const -9))
+                (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_attach_struct_ops() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_struct_ops_attach" (func $attach (param i64 i32) (result i32)))
+        (data (i32.const 16) "rb\00")
+        (data (i32.const 32) "no_such_map\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            ;; Not a struct_ops map
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 16)) (i32.const -22))
+                (then unreachable))
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 32)) (i32.const -2))
+                (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_set_attach_target_to_program() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_open_bpf_object" (func $open (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_program_set_attach_target"
+            (func $set_attach_target (param i64 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_program_fd_by_name" (func $prog_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_program_fd_by_id" (func $prog_fd_by_id (param i32) (result i32)))
+        (data (i32.const 16) "handle_exec\00")
+        (data (i32.const 32) "handle_exit\00")
+        (data (i32.const 48) "no_such_prog\00")
+        (data (i32.const 64) "wasm_bpf_no_such_function\00")
+        (func (export "_start")
+            (local $loaded i64)
+            (local $opened i64)
+            (local $fd i32)
+            (local.set $loaded (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $loaded)) (then unreachable))
+            (local.set $opened (call $open (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $opened)) (then unreachable))
+            (local.set $fd (call $prog_fd (local.get $loaded) (i32.const 16)))
+            (if (i32.le_s (local.get $fd) (i32.const 0)) (then unreachable))
+            ;; Programs of objects not loaded have no fds
+            (if (i32.ge_s (call $prog_fd (local.get $opened) (i32.const 16)) (i32.const 0))
+                (then unreachable))
+            (if (i32.ge_s (call $prog_fd (local.get $loaded) (i32.const 48)) (i32.const 0))
+                (then unreachable))
+            ;; Trace a function of a loaded program
+            (if (call $set_attach_target (local.get $opened) (i32.const 32) (local.get $fd) (i32.const 16))
+                (then unreachable))
+            (if (i32.ge_s (call $set_attach_target (local.get $opened) (i32.const 32) (local.get $fd) (i32.const 64))
+                    (i32.const 0))
+                (then unreachable))
+            (if (i32.ge_s (call $set_attach_target (local.get $opened) (i32.const 48) (i32.const 0) (i32.const 0))
+                    (i32.const 0))
+                (then unreachable))
+            ;; Only opened objects are configurable
+            (if (i32.ge_s (call $set_attach_target (local.get $loaded) (i32.const 32) (local.get $fd) (i32.const 16))
+                    (i32.const 0))
+                (then unreachable))
+            (if (i32.ge_s (call $prog_fd_by_id (i32.const -1)) (i32.const 0))
+                (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_report_load_error() {
+    let err = run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_open_bpf_object" (func $open (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_object_load" (func $load (param i64) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_set_max_entries" (func $set_max_entries (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_last_error" (func $last_error (param i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_last_errno" (func $last_errno (result i32)))
+        (data (i32.const 16) "exec_start\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local $len i32)
+            (if (call $last_error (i32.const 64) (i32.const 1024)) (then unreachable))
+            (if (call $last_errno) (then unreachable))
+            (local.set $obj (call $open (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            ;; Hash maps can't be empty
+            (if (call $set_max_entries (local.get $obj) (i32.const 16) (i32.const 0)) (then unreachable))
+            ;; EINVAL from the kernel
+            (if (i32.ne (call $load (local.get $obj)) (i32.const -22)) (then unreachable))
+            (if (i32.ne (call $last_errno) (i32.const -22)) (then unreachable))
+            ;; Already loaded
+            (if (i32.ne (call $load (local.get $obj)) (i32.const -9)) (then unreachable))
+            (local.set $len (call $last_error (i32.const 64) (i32.const 8)))
+            (if (i32.le_s (local.get $len) (i32.const 8)) (then unreachable))
+            ;; Truncated with a terminating null
+            (if (i32.ne (i32.load8_u (i32.const 64)) (i32.const 70)) (then unreachable))
+            (if (i32.load8_u (i32.const 71)) (then unreachable))
+            (if (i32.ne (call $last_error (i32.const 0) (i32.const 0)) (local.get $len)) (then unreachable))
+            ;; Fail the program to pass the error to the embedder
+            unreachable)
+        "#,
+    )
+    .unwrap_err();
+    let load_error = err.downcast_ref::<BpfLoadError>().unwrap();
+    assert!(load_error.message.starts_with("Failed to load bpf object"));
+    assert_eq!(load_error.errno, -22);
+    assert!(load_error.log.contains("map 'exec_start'"));
+}
+
+#[test]
+fn test_load_error_cleared_by_next_attempt() {
+    let err = run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_last_errno" (func $last_errno (result i32)))
+        (func (export "_start")
+            ;; Not an ELF file
+            (if (i64.ne (call $load (i32.const 0) (i32.const 64)) (i64.const 0)) (then unreachable))
+            (if (i32.eqz (call $last_errno)) (then unreachable))
+            (if (i64.eqz (call $load (i32.const 4096) (global.get $obj_size))) (then unreachable))
+            (if (call $last_errno) (then unreachable))
+            ;; Fail the program for something unrelated to the failed load
+            unreachable)
+        "#,
+    )
+    .unwrap_err();
+    assert!(err.downcast_ref::<BpfLoadError>().is_none());
+}
+
+#[test]
+fn test_negative_errno_of_host_functions() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_last_errno" (func $last_errno (result i32)))
+        (import "wasm_bpf" "wasm_close_bpf_object" (func $close (param i64) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_fd_by_name" (func $map_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_program_attach" (func $attach (param i64 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_operate" (func $map_operate (param i32 i32 i32 i32 i32 i64) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_buffer_poll" (func $poll (param i64 i32 i32 i32 i32 i32 i32) (result i32)))
+        (data (i32.const 16) "exec_start\00")
+        (data (i32.const 32) "no_such_map\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local $fd i32)
+            ;; The object is out of the memory
+            (if (i64.ne (call $load (i32.const 0x7fff0000) (global.get $obj_size)) (i64.const 0))
+                (then unreachable))
+            (if (i32.ne (call $last_errno) (i32.const -14)) (then unreachable))
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            ;; EBADF for invalid handles and fds
+            (if (i32.ne (call $map_fd (i64.const 12345) (i32.const 16)) (i32.const -9)) (then unreachable))
+            (if (i32.ne (call $attach (i64.const 12345) (i32.const 16) (i32.const 0)) (i32.const -9))
+                (then unreachable))
+            (if (i32.ne (call $close (i64.const 12345)) (i32.const -9)) (then unreachable))
+            (if (i32.ne (call $map_operate (i32.const -1) (i32.const 1) (i32.const 64) (i32.const 72) (i32.const 0) (i64.const 0))
+                    (i32.const -9))
+                (then unreachable))
+            ;; ENOENT for names not found
+            (if (i32.ne (call $map_fd (local.get $obj) (i32.const 32)) (i32.const -2)) (then unreachable))
+            (if (i32.ne (call $attach (local.get $obj) (i32.const 32) (i32.const 0)) (i32.const -2))
+                (then unreachable))
+            ;; EFAULT for pointers out of the memory
+            (if (i32.ne (call $map_fd (local.get $obj) (i32.const 0x7fff0000)) (i32.const -14))
+                (then unreachable))
+            (local.set $fd (call $map_fd (local.get $obj) (i32.const 16)))
+            (if (i32.lt_s (local.get $fd) (i32.const 0)) (then unreachable))
+            (if (i32.ne (call $map_operate (local.get $fd) (i32.const 1) (i32.const 0x7fff0000) (i32.const 72) (i32.const 0) (i64.const 0))
+                    (i32.const -14))
+                (then unreachable))
+            ;; EINVAL for invalid arguments
+            (if (i32.ne (call $map_operate (local.get $fd) (i32.const 100) (i32.const 64) (i32.const 72) (i32.const 0) (i64.const 0))
+                    (i32.const -22))
+                (then unreachable))
+            ;; Hash maps can't be polled
+            (if (i32.ne (call $poll (local.get $obj) (local.get $fd) (i32.const 0) (i32.const 0) (i32.const 128) (i32.const 64) (i32.const 0))
+                    (i32.const -22))
+                (then unreachable))
+            ;; The errno of the kernel otherwise, like ENOENT for missing keys
+            (if (i32.ne (call $map_operate (local.get $fd) (i32.const 1) (i32.const 64) (i32.const 72) (i32.const 0) (i64.const 0))
+                    (i32.const -2))
+                (then unreachable))
+            (if (call $close (local.get $obj)) (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_query_abi_version_and_capabilities() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_bpf_get_abi_version" (func $abi_version (result i32)))
+        (import "wasm_bpf" "wasm_bpf_get_capabilities" (func $capabilities (result i64)))
+        (import "wasm_bpf" "wasm_bpf_get_features" (func $features (param i32 i32) (result i32)))
+        (func (export "_start")
+            (local $len i32)
+            (if (i32.ne (call $abi_version) (i32.const 2)) (then unreachable))
+            (if (i64.ne (call $capabilities) (i64.const 0xffff)) (then unreachable))
+            (local.set $len (call $features (i32.const 0) (i32.const 0)))
+            ;; Truncated with a terminating null
+            (if (i32.ne (call $features (i32.const 64) (i32.const 4)) (local.get $len)) (then unreachable))
+            (if (i32.ne (i32.load8_u (i32.const 66)) (i32.const 111)) (then unreachable))
+            (if (i32.load8_u (i32.const 67)) (then unreachable))
+            ;; `two_phase_load,global_var,...`
+            (if (i32.ne (call $features (i32.const 64) (i32.const 1024)) (local.get $len)) (then unreachable))
+            (if (i32.ne (i32.load8_u (i32.const 78)) (i32.const 44)) (then unreachable))
+            (if (i32.load8_u (i32.add (i32.const 64) (local.get $len))) (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_report_missing_host_functions() {
+    let err = run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_bpf_map_operate" (func $map_operate (param i32 i32 i32 i32 i32 i64) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_from_the_future" (func $from_the_future (result i32)))
+        (func (export "_start"))
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(err.contains("unknown functions `wasm_bpf_from_the_future`"));
+    assert!(!err.contains("wasm_bpf_map_operate"));
+    assert!(err.contains("ABI version newer than 2"));
+    // Functions known by the runtime are named with the capability providing them
+    let message =
+        missing_imports_message(&["wasm_bpf_btf_to_json", "wasm_bpf_from_the_future"]).unwrap();
+    assert!(message.contains("`wasm_bpf_btf_to_json` of capability `btf_json` (bit 14)"));
+    assert!(message.contains("unknown functions `wasm_bpf_from_the_future`"));
+    let message = missing_imports_message(&["wasm_bpf_map_operate"]).unwrap();
+    assert!(message.contains("`wasm_bpf_map_operate` of ABI version 1"));
+    assert!(!message.contains("unknown"));
+    assert_eq!(missing_imports_message(&[]), None);
+}
+
+#[test]
+fn test_list_programs_and_maps_of_object() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_object_programs" (func $programs (param i64 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_object_maps" (func $maps (param i64 i32 i32 i32) (result i32)))
+        (func (export "_start")
+            (local $obj i64)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (if (i32.ne (call $programs (local.get $obj) (i32.const 0) (i32.const 0) (i32.const 0)) (i32.const 2))
+                (then unreachable))
+            (if (i32.ne (call $programs (local.get $obj) (i32.const 256) (i32.const 144) (i32.const 4)) (i32.const 2))
+                (then unreachable))
+            ;; handle_exec in tp/sched/sched_process_exec
+            (if (i32.ne (i32.load8_u (i32.const 263)) (i32.const 101)) (then unreachable))
+            (if (i32.ne (i32.load8_u (i32.const 320)) (i32.const 116)) (then unreachable))
+            ;; BPF_PROG_TYPE_TRACEPOINT
+            (if (i32.ne (i32.load (i32.const 384)) (i32.const 5)) (then unreachable))
+            (if (i32.le_s (i32.load (i32.const 388)) (i32.const 0)) (then unreachable))
+            (if (i64.eqz (i64.load (i32.const 392))) (then unreachable))
+            ;; handle_exit
+            (if (i32.ne (i32.load8_u (i32.const 408)) (i32.const 120)) (then unreachable))
+            ;; Only the fields known by the caller are written
+            (memory.fill (i32.const 256) (i32.const 0xff) (i32.const 512))
+            (if (i32.ne (call $programs (local.get $obj) (i32.const 256) (i32.const 132) (i32.const 1)) (i32.const 2))
+                (then unreachable))
+            (if (i32.ne (i32.load (i32.const 384)) (i32.const 5)) (then unreachable))
+            (if (i32.ne (i32.load (i32.const 388)) (i32.const -1)) (then unreachable))
+            ;; Bytes beyond the fields known by the runtime are zeroed
+            (if (i32.ne (call $programs (local.get $obj) (i32.const 256) (i32.const 152) (i32.const 1)) (i32.const 2))
+                (then unreachable))
+            (if (i64.ne (i64.load (i32.const 400)) (i64.const 0)) (then unreachable))
+            (if (i32.ne (call $programs (local.get $obj) (i32.const 0x7fff0000) (i32.const 144) (i32.const 1)) (i32.const -14))
+                (then unreachable))
+            (if (i32.ne (call $programs (i64.const 12345) (i32.const 256) (i32.const 144) (i32.const 1)) (i32.const -9))
+                (then unreachable))
+            ;; exec_start, rb, and .rodata
+            (if (i32.ne (call $maps (local.get $obj) (i32.const 256) (i32.const 88) (i32.const 1)) (i32.const 3))
+                (then unreachable))
+            (if (i32.ne (i32.load8_u (i32.const 256)) (i32.const 101)) (then unreachable))
+            ;; BPF_MAP_TYPE_HASH of pid_t to u64, with 8192 entries
+            (if (i32.ne (i32.load (i32.const 320)) (i32.const 1)) (then unreachable))
+            (if (i32.ne (i32.load (i32.const 324)) (i32.const 4)) (then unreachable))
+            (if (i32.ne (i32.load (i32.const 328)) (i32.const 8)) (then unreachable))
+            (if (i32.ne (i32.load (i32.const 332)) (i32.const 8192)) (then unreachable))
+            (if (i32.le_s (i32.load (i32.const 340)) (i32.const 0)) (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_decode_btf_values_into_json() {
+    let module_binary = build_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_map_fd_by_name" (func $map_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_object_maps" (func $maps (param i64 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_value_to_json" (func $map_value_to_json (param i64 i32 i32 i32 i32 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_btf_to_json" (func $btf_to_json (param i64 i32 i32 i32 i32 i32) (result i32)))
+        (data (i32.const 16) "exec_start\00")
+        (data (i32.const 32) "rb\00")
+        (data (i32.const 64) "struct trace_entry\00")
+        (data (i32.const 96) "struct no_such_type\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            ;; The u64 value of exec_start
+            (i64.store (i32.const 512) (i64.const 123456789))
+            (if (i32.ne (call $map_value_to_json (local.get $obj) (call $map_fd (local.get $obj) (i32.const 16))
+                    (i32.const 512) (i32.const 8) (i32.const 1024) (i32.const 64)) (i32.const 9))
+                (then unreachable))
+            (if (i32.ne (i32.load8_u (i32.const 1024)) (i32.const 49)) (then unreachable))
+            (if (i32.load8_u (i32.const 1033)) (then unreachable))
+            ;; Ring buffers don't declare the type of their values
+            (if (i32.ne (call $map_value_to_json (local.get $obj) (call $map_fd (local.get $obj) (i32.const 32))
+                    (i32.const 512) (i32.const 8) (i32.const 1024) (i32.const 64)) (i32.const -2))
+                (then unreachable))
+            (if (i32.ne (call $map_value_to_json (local.get $obj) (i32.const 12345)
+                    (i32.const 512) (i32.const 8) (i32.const 1024) (i32.const 64)) (i32.const -9))
+                (then unreachable))
+            ;; The datasec of .rodata, whose name depends on the object, so its fd is taken from the map info
+            (if (i32.ne (call $maps (local.get $obj) (i32.const 2048) (i32.const 88) (i32.const 3)) (i32.const 3))
+                (then unreachable))
+            (i64.store (i32.const 512) (i64.const 1000))
+            (if (i32.le_s (call $map_value_to_json (local.get $obj) (i32.load (i32.const 2308))
+                    (i32.const 512) (i32.const 8) (i32.const 0) (i32.const 0)) (i32.const 0))
+                (then unreachable))
+            ;; struct trace_entry { u16 type; u8 flags; u8 preempt_count; int pid; }
+            (i32.store (i32.const 600) (i32.const 0x02010007))
+            (i32.store (i32.const 604) (i32.const -5))
+            (if (i32.le_s (call $btf_to_json (local.get $obj) (i32.const 64)
+                    (i32.const 600) (i32.const 8) (i32.const 0) (i32.const 0)) (i32.const 0))
+                (then unreachable))
+            (if (i32.ne (call $btf_to_json (local.get $obj) (i32.const 64)
+                    (i32.const 600) (i32.const 7) (i32.const 0) (i32.const 0)) (i32.const -22))
+                (then unreachable))
+            (if (i32.ne (call $btf_to_json (local.get $obj) (i32.const 96)
+                    (i32.const 600) (i32.const 8) (i32.const 0) (i32.const 0)) (i32.const -2))
+                (then unreachable))
+            (if (i32.ne (call $btf_to_json (local.get $obj) (i32.const 64)
+                    (i32.const 0x7fff0000) (i32.const 8) (i32.const 0) (i32.const 0)) (i32.const -14))
+                (then unreachable)))
+        "#,
+    );
+    let (tx, rx) = mpsc::channel();
+    let mut config = Config::default();
+    config.set_json_sender(tx);
+    let args = ["test".to_string()];
+    WasmBpfModuleRunner::new(&module_binary[..], &args[..], config)
+        .unwrap()
+        .into_engine_and_entry_func()
+        .unwrap()
+        .1
+        .run()
+        .unwrap();
+    let values: Vec<_> = rx.try_iter().collect();
+    assert_eq!(
+        values,
+        vec![
+            serde_json::json!(123456789),
+            serde_json::json!({ "min_duration_ns": 1000 }),
+            serde_json::json!({ "type": 7, "flags": 1, "preempt_count": 2, "pid": -5 }),
+        ]
+    );
+}
+
+#[test]
+fn test_decode_malformed_btf_into_json() {
+    // SAFETY: the BTF is only used in the test, and freed at the end
+    unsafe {
+        let btf = btf__new_empty();
+        let name = |v: &str| CString::new(v).unwrap();
+        let int = btf__add_int(btf, name("int").as_ptr(), 4, BTF_INT_SIGNED as i32);
+        let signed_char = btf__add_int(
+            btf,
+            name("signed char").as_ptr(),
+            1,
+            (BTF_INT_SIGNED | BTF_INT_CHAR) as i32,
+        );
+        // Clang doesn't set BTF_INT_CHAR
+        let unsigned_char = btf__add_int(btf, name("unsigned char").as_ptr(), 1, 0);
+        let signed_array = btf__add_array(btf, int, signed_char, 4);
+        let unsigned_array = btf__add_array(btf, int, unsigned_char, 4);
+        let names = btf__add_struct(btf, name("names").as_ptr(), 8);
+        btf__add_field(btf, name("a").as_ptr(), signed_array, 0, 0);
+        btf__add_field(btf, name("b").as_ptr(), unsigned_array, 32, 0);
+        // A struct containing itself can only come from malformed BTF
+        let node = btf__add_struct(btf, name("node").as_ptr(), 4);
+        btf__add_field(btf, name("next").as_ptr(), node, 0, 0);
+        let huge_array = btf__add_array(btf, int, int, 0x7fffffff);
+        let empty = btf__add_struct(btf, name("empty").as_ptr(), 0);
+        let empty_array = btf__add_array(btf, int, empty, 0x7fffffff);
+        let var = btf__add_var(btf, name("v").as_ptr(), 1, int);
+        let datasec = btf__add_datasec(btf, name(".data").as_ptr(), 16);
+        btf__add_datasec_var_info(btf, var, 0xfffffff0, 0x20);
+        assert!(datasec > 0);
+
+        let decoder = BtfDecoder::from_btf(NonNull::new(btf).unwrap());
+        assert_eq!(
+            decoder.decode(names as u32, b"ab\0\0cde\0"),
+            Ok(serde_json::json!({ "a": "ab", "b": "cde" }))
+        );
+        assert_eq!(decoder.decode(node as u32, &[0; 4]), Err(-libc::EINVAL));
+        assert_eq!(
+            decoder.decode(huge_array as u32, &[0; 16]),
+            Err(-libc::EINVAL)
+        );
+        let empties = decoder.decode(empty_array as u32, &[]).unwrap();
+        assert_eq!(empties.as_array().unwrap().len(), 1024);
+        assert_eq!(decoder.decode(datasec as u32, &[0; 16]), Err(-libc::EINVAL));
+        btf__free(btf);
+    }
+}
+
+#[test]
+fn test_batch_map_operations() {
+    run_wat_guest_with_bpf_object(
+        r#"
+        (import "wasm_bpf" "wasm_load_bpf_object" (func $load (param i32 i32) (result i64)))
+        (import "wasm_bpf" "wasm_bpf_map_fd_by_name" (func $map_fd (param i64 i32) (result i32)))
+        (import "wasm_bpf" "wasm_bpf_map_operate" (func $map_operate (param i32 i32 i32 i32 i32 i64) (result i32)))
+        (data (i32.const 16) "exec_start\00")
+        ;; struct wasm_bpf_map_batch of 3 pid_t keys and u64 values to update and delete
+        (data (i32.const 128) "\00\00\00\00\00\00\00\00\00\01\00\00\00\02\00\00\03\00\00\00")
+        (data (i32.const 256) "\01\00\00\00\02\00\00\00\03\00\00\00")
+        (data (i32.const 512) "\64\00\00\00\00\00\00\00\c8\00\00\00\00\00\00\00\2c\01\00\00\00\00\00\00")
+        ;; struct wasm_bpf_map_batch to look up at most 8 elements
+        (data (i32.const 160) "\00\00\00\00\00\04\00\00\00\05\00\00\00\06\00\00\08\00\00\00")
+        (func (export "_start")
+            (local $obj i64)
+            (local $fd i32)
+            (local.set $obj (call $load (i32.const 4096) (global.get $obj_size)))
+            (if (i64.eqz (local.get $obj)) (then unreachable))
+            (local.set $fd (call $map_fd (local.get $obj) (i32.const 16)))
+            ;; BPF_MAP_UPDATE_BATCH
+            (if (call $map_operate (local.get $fd) (i32.const 26) (i32.const 128) (i32.const 0) (i32.const 0) (i64.const 0))
+                (then unreachable))
+            (if (i32.ne (i32.load (i32.const 144)) (i32.const 3)) (then unreachable))
+            ;; BPF_MAP_LOOKUP_BATCH reaches the end of the map
+            (if (i32.ne (call $map_operate (local.get $fd) (i32.const 24) (i32.const 160) (i32.const 0) (i32.const 0) (i64.const 0)) (i32.const -2))
+                (then unreachable))
+            (if (i32.ne (i32.load (i32.const 176)) (i32.const 3)) (then unreachable))
+            (if (i64.ne (i64.add (i64.add (i64.load (i32.const 1536)) (i64.load (i32.const 1544))) (i64.load (i32.const 1552))) (i64.const 600))
+                (then unreachable))
+            ;; BPF_MAP_DELETE_BATCH
+            (if (call $map_operate (local.get $fd) (i32.const 27) (i32.const 128) (i32.const 0) (i32.const 0) (i64.const 0))
+                (then unreachable))
+            (if (i32.ne (call $map_operate (local.get $fd) (i32.const 1) (i32.const 256) (i32.const 1536) (i32.const 0) (i64.const 0)) (i32.const -2))
+                (then unreachable))
+            ;; The arrays and cursors are checked against the guest memory
+            (i32.store (i32.const 176) (i32.const 0x10000000))
+            (if (i32.ne (call $map_operate (local.get $fd) (i32.const 24) (i32.const 160) (i32.const 0) (i32.const 0) (i64.const 0)) (i32.const -14))
+                (then unreachable))
+            (i32.store (i32.const 176) (i32.const 8))
+            (i32.store (i32.const 164) (i32.const 0))
+            (if (i32.ne (call $map_operate (local.get $fd) (i32.const 24) (i32.const 160) (i32.const 0) (i32.const 0) (i64.const 0)) (i32.const -22))
+                (then unreachable))
+            (i32.store (i32.const 164) (i32.const 0x7fff0000))
+            (if (i32.ne (call $map_operate (local.get $fd) (i32.const 24) (i32.const 160) (i32.const 0) (i32.const 0) (i64.const 0)) (i32.const -14))
+                (then unreachable))
+            (if (i32.ne (call $map_operate (local.get $fd) (i32.const 26) (i32.const 0x7fffffe0) (i32.const 0) (i32.const 0) (i64.const 0)) (i32.const -14))
+                (then unreachable)))
+        "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_batch_map_operations_fall_back_for_maps_without_them() {
+    let opts = bpf_map_create_opts {
+        sz: std::mem::size_of::<bpf_map_create_opts>() as _,
+        map_flags: BPF_F_NO_PREALLOC,
+        ..Default::default()
+    };
+    // LPM tries don't implement the batch commands.
+    // SAFETY: the options live through the call
+    let map_fd =
+        unsafe { bpf_map_create(BPF_MAP_TYPE_LPM_TRIE, std::ptr::null(), 8, 8, 16, &opts) };
+    assert!(map_fd >= 0);
+    let fd = map_fd;
+    run_wat_guest_with_bpf_object(&format!(
+        r#"
+        (import "wasm_bpf" "wasm_bpf_map_operate" (func $map_operate (param i32 i32 i32 i32 i32 i64) (result i32)))
+        ;; struct wasm_bpf_map_batch of 2 keys of 10.0.0.1/32 and 10.0.0.2/32, and u64 values
+        (data (i32.const 128) "\00\00\00\00\00\00\00\00\00\01\00\00\00\02\00\00\02\00\00\00")
+        (data (i32.const 256) "\20\00\00\00\0a\00\00\01\20\00\00\00\0a\00\00\02")
+        (data (i32.const 512) "\07\00\00\00\00\00\00\00\09\00\00\00\00\00\00\00")
+        (data (i32.const 160) "\00\00\00\00\00\04\00\00\00\05\00\00\00\06\00\00\08\00\00\00")
+        (func (export "_start")
+            (if (call $map_operate (i32.const {fd}) (i32.const 26) (i32.const 128) (i32.const 0) (i32.const 0) (i64.const 0))
+                (then unreachable))
+            (if (i32.ne (i32.load (i32.const 144)) (i32.const 2)) (then unreachable))
+            (if (i32.ne (call $map_operate (i32.const {fd}) (i32.const 24) (i32.const 160) (i32.const 0) (i32.const 0) (i64.const 0)) (i32.const -2))
+                (then unreachable))
+            (if (i32.ne (i32.load (i32.const 176)) (i32.const 2)) (then unreachable))
+            (if (i64.ne (i64.add (i64.load (i32.const 1536)) (i64.load (i32.const 1544))) (i64.const 16))
+                (then unreachable))
+            (if (call $map_operate (i32.const {fd}) (i32.const 27) (i32.const 128) (i32.const 0) (i32.const 0) (i64.const 0))
+                (then unreachable))
+            (if (i32.ne (call $map_operate (i32.const {fd}) (i32.const 1) (i32.const 256) (i32.const 1536) (i32.const 0) (i64.const 0)) (i32.const -2))
+                (then unreachable)))
+        "#
+    ))
+    .unwrap();
+    // SAFETY: the fd is owned here
+    unsafe { libc::close(map_fd) };
+}
+
+#[test]
+fn test_fall_back_to_map_operations_one_by_one() {
+    // SAFETY: no pointers are passed
+    let map_fd = unsafe {
+        bpf_map_create(
+            BPF_MAP_TYPE_HASH,
+            std::ptr::null(),
+            4,
+            8,
+            16,
+            std::ptr::null(),
+        )
+    };
+    assert!(map_fd >= 0);
+    let keys: Vec<u8> = (1..=5u32).flat_map(|v| v.to_le_bytes()).collect();
+    let values: Vec<u8> = (1..=5u64).flat_map(|v| (v * 10).to_le_bytes()).collect();
+    let mut count = 5;
+    // SAFETY: the buffers hold `count` keys and values
+    let ret = unsafe {
+        update_batch_fallback(map_fd, keys.as_ptr(), values.as_ptr(), &mut count, 0, 4, 8)
+    };
+    assert_eq!((ret, count), (0, 5));
+
+    // Look up in batches of 3, continuing from the cursor
+    let mut found = vec![];
+    let mut in_batch: [u8; 4];
+    let mut out_batch = [0u8; 4];
+    let mut in_batch_ptr = std::ptr::null();
+    loop {
+        let mut keys = [0u8; 12];
+        let mut values = [0u8; 24];
+        let mut count = 3;
+        // SAFETY: the buffers hold `count` keys and values, and the cursors hold a key
+        let ret = unsafe {
+            lookup_batch_fallback(
+                map_fd,
+                in_batch_ptr,
+                out_batch.as_mut_ptr(),
+                keys.as_mut_ptr(),
+                values.as_mut_ptr(),
+                &mut count,
+                0,
+                false,
+                4,
+                8,
+            )
+        };
+        for i in 0..count as usize {
+            let key = u32::from_le_bytes(keys[i * 4..i * 4 + 4].try_into().unwrap());
+            let value = u64::from_le_bytes(values[i * 8..i * 8 + 8].try_into().unwrap());
+            found.push((key, value));
+        }
+        if ret == -libc::ENOENT {
+            break;
+        }
+        assert_eq!((ret, count), (0, 3));
+        in_batch = out_batch;
+        in_batch_ptr = in_batch.as_ptr();
+    }
+    found.sort();
+    assert_eq!(
+        found,
+        (1..=5).map(|v| (v, v as u64 * 10)).collect::<Vec<_>>()
+    );
+
+    let mut keys = [0u8; 32];
+    let mut values = [0u8; 64];
+    let mut count = 8;
+    // SAFETY: the buffers hold `count` keys and values, and the cursor holds a key
+    let ret = unsafe {
+        lookup_batch_fallback(
+            map_fd,
+            std::ptr::null(),
+            out_batch.as_mut_ptr(),
+            keys.as_mut_ptr(),
+            values.as_mut_ptr(),
+            &mut count,
+            0,
+            true,
+            4,
+            8,
+        )
+    };
+    assert_eq!((ret, count), (-libc::ENOENT, 5));
+    // Deleting stops at the first key not found
+    let mut count = 2;
+    // SAFETY: the buffer holds `count` keys
+    let ret = unsafe {
+        update_batch_fallback(map_fd, keys.as_ptr(), std::ptr::null(), &mut count, 0, 4, 8)
+    };
+    assert_eq!((ret, count), (-libc::ENOENT, 0));
+    // SAFETY: the fd is owned here
+    unsafe { libc::close(map_fd) };
+}
@@ -15,8 +15,10 @@ pub trait CallerUtils {
     fn get_memory(&mut self) -> anyhow::Result<Memory>;
     fn get_indirect_call_table(&mut self) -> anyhow::Result<Table>;
     // Terminated zero won't be put in the returned Vec
+    #[allow(dead_code)]
     fn read_wasm_string(&mut self, offset: usize) -> anyhow::Result<Vec<u8>>;
     // Terminated zero won't be included
+    #[allow(dead_code)]
     fn read_wasm_string_slice(&mut self, offset: usize) -> anyhow::Result<&[u8]>;
     // Terminated zero will be included
     fn read_wasm_string_slice_include_zero(&mut self, offset: usize) -> anyhow::Result<&[u8]>;
@@ -89,7 +91,7 @@ impl CallerUtils for Caller<'_, AppState> {
                 at += 1;
             }
         }
-        return Ok(&memory.data(self)[offset..=at]);
+        Ok(&memory.data(self)[offset..=at])
     }
 
     fn read_zero_terminated_str(&mut self, offset: usize) -> anyhow::Result<&str> {
@@ -97,9 +99,9 @@ impl CallerUtils for Caller<'_, AppState> {
             .read_wasm_string_slice_include_zero(offset)
             .with_context(|| anyhow!("Failed to read byte slice"))?;
         let c_str = CStr::from_bytes_with_nul(data_slice).unwrap();
-        return c_str
+        c_str
             .to_str()
-            .with_context(|| anyhow!("Failed to decode bytes into utf8 str"));
+            .with_context(|| anyhow!("Failed to decode bytes into utf8 str"))
     }
 
     unsafe fn raw_pointer_at_unchecked(&mut self, offset: usize) -> *const u8 {